@@ -11,7 +11,7 @@ use tcore::reactor::Core;
 use slacker::ClientManager;
 
 fn main() {
-    drop(env_logger::init());
+    env_logger::init();
 
     let mut core = Core::new().unwrap();
 
@@ -27,5 +27,6 @@ fn main() {
                 println!("{:?}", r);
                 Ok(())
             }),
-    ).unwrap();
+    )
+    .unwrap();
 }
@@ -0,0 +1,40 @@
+extern crate futures;
+extern crate slacker;
+#[macro_use]
+extern crate serde_json;
+extern crate env_logger;
+extern crate tokio_core as tcore;
+
+use futures::Future;
+use tcore::reactor::Core;
+
+use slacker::{ClientManager, ClusterClient, LoadBalance, StaticDiscovery};
+
+use std::sync::Arc;
+
+fn main() {
+    env_logger::init();
+
+    let mut core = Core::new().unwrap();
+
+    let discovery = StaticDiscovery::new(vec![
+        "127.0.0.1:3299".parse().unwrap(),
+        "127.0.0.1:3300".parse().unwrap(),
+    ]);
+    let client = ClusterClient::new(
+        ClientManager::new(),
+        core.handle(),
+        Arc::new(discovery),
+        LoadBalance::RoundRobin,
+    );
+
+    core.run(
+        client
+            .rpc_call("rust.test", "echo", vec![json!(1), json!(2)])
+            .and_then(|r| {
+                println!("{:?}", r);
+                Ok(())
+            }),
+    )
+    .unwrap();
+}
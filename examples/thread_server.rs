@@ -19,7 +19,7 @@ fn echo(s: &Vec<Json>) -> Json {
 }
 
 fn main() {
-    drop(env_logger::init());
+    env_logger::init();
 
     let funcs = btreemap! {
         "rust.test/echo".to_owned() => Arc::new(echo) as JsonRpcFnSync
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// A `host:port` TCP address or a `unix:/path` Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SlackerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for SlackerAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<SlackerAddr> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Empty unix socket path.",
+                ))
+            } else {
+                Ok(SlackerAddr::Unix(PathBuf::from(path)))
+            }
+        } else {
+            s.parse()
+                .map(SlackerAddr::Tcp)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        }
+    }
+}
+
+impl fmt::Display for SlackerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SlackerAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            SlackerAddr::Unix(ref path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for SlackerAddr {
+    fn from(addr: SocketAddr) -> SlackerAddr {
+        SlackerAddr::Tcp(addr)
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
+use futures::future::ok;
+use futures::Future;
+use serde_json;
+use tservice::Service;
+
+use interceptor::ConnectionInfo;
+use parser::*;
+use service::{error_response, matches_fname};
+
+/// Resolves the principal of a connection, either from the token a client
+/// sends in the v6 auth extension or, when there is none, from the
+/// connection itself (e.g. its TLS client certificate).
+///
+/// Once a connection is authenticated the principal sticks, so a client may
+/// send its token with the first request only.
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate(&self, conn: &ConnectionInfo, token: Option<&[u8]>) -> Option<String>;
+}
+
+pub trait Authorizer: Send + Sync + 'static {
+    fn authorize(&self, principal: &str, fname: &str) -> bool;
+}
+
+/// Grants principals access by pattern: `*`, a namespace like `rust.test/*`,
+/// or a full function name like `rust.test/echo`.
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: BTreeMap<String, Vec<String>>,
+}
+
+impl Acl {
+    pub fn new() -> Acl {
+        Acl::default()
+    }
+
+    pub fn allow(mut self, principal: &str, pattern: &str) -> Acl {
+        self.rules
+            .entry(principal.to_owned())
+            .or_default()
+            .push(pattern.to_owned());
+        self
+    }
+}
+
+impl Authorizer for Acl {
+    fn authorize(&self, principal: &str, fname: &str) -> bool {
+        match self.rules.get(principal) {
+            Some(patterns) => patterns.iter().any(|pattern| matches_fname(pattern, fname)),
+            None => false,
+        }
+    }
+}
+
+pub struct Authenticated<S> {
+    inner: S,
+    conn: Arc<ConnectionInfo>,
+    authenticator: Arc<dyn Authenticator>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+}
+
+impl<S> Authenticated<S> {
+    pub fn new(
+        inner: S,
+        conn: Arc<ConnectionInfo>,
+        authenticator: Arc<dyn Authenticator>,
+        authorizer: Option<Arc<dyn Authorizer>>,
+    ) -> Authenticated<S> {
+        Authenticated {
+            inner,
+            conn,
+            authenticator,
+            authorizer,
+        }
+    }
+
+    // Inspect requests carry no token, they rely on an earlier request or
+    // on what the connection itself tells, e.g. its client certificate.
+    fn check(&self, token: Option<&[u8]>, fname: Option<&str>) -> bool {
+        if token.is_some() || self.conn.principal().is_none() {
+            match self.authenticator.authenticate(&self.conn, token) {
+                Some(principal) => self.conn.set_principal(principal),
+                None => {
+                    debug!("authentication failed for {}", self.conn.peer_name());
+                    return false;
+                }
+            }
+        }
+
+        match (self.conn.principal(), &self.authorizer, fname) {
+            (Some(ref principal), Some(authorizer), Some(fname)) => {
+                authorizer.authorize(principal, fname)
+            }
+            (principal, _, _) => principal.is_some(),
+        }
+    }
+}
+
+impl<S> Service for Authenticated<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let allowed = match req {
+            // notifications are the connection's own, not those of a function
+            SlackerPacket(_, SlackerPacketBody::Request(ref sreq)) => self.check(
+                find_extension(&sreq.extensions, EXTENSION_ID_AUTH_TOKEN),
+                if find_extension(&sreq.extensions, EXTENSION_ID_NOTIFICATIONS).is_some() {
+                    None
+                } else {
+                    Some(&sreq.fname)
+                },
+            ),
+            // the metadata of a function is subject to its ACL
+            SlackerPacket(_, SlackerPacketBody::InspectRequest(ref ireq)) => {
+                if ireq.inspect_type == INSPECT_TYPE_META {
+                    self.check(None, Some(&String::from_utf8_lossy(&ireq.data)))
+                } else {
+                    self.check(None, None)
+                }
+            }
+            _ => true,
+        };
+
+        if !allowed {
+            let SlackerPacket(header, _) = req;
+            return Box::new(ok(error_response(header, RESULT_CODE_ACL_REJECT)));
+        }
+
+        let lists_functions = match req {
+            SlackerPacket(_, SlackerPacketBody::InspectRequest(ref ireq)) => {
+                ireq.inspect_type == INSPECT_TYPE_FUNCTIONS
+            }
+            _ => false,
+        };
+        match (&self.authorizer, self.conn.principal()) {
+            (Some(authorizer), Some(principal)) if lists_functions => {
+                let authorizer = authorizer.clone();
+                Box::new(
+                    self.inner
+                        .call(req)
+                        .map(move |resp| authorized_functions(resp, &*authorizer, &principal)),
+                )
+            }
+            _ => Box::new(self.inner.call(req)),
+        }
+    }
+}
+
+// Leaves out of a function list the functions the principal may not call.
+fn authorized_functions(
+    resp: SlackerPacket,
+    authorizer: &dyn Authorizer,
+    principal: &str,
+) -> SlackerPacket {
+    match resp {
+        SlackerPacket(header, SlackerPacketBody::InspectResponse(iresp)) => {
+            let fnames: Vec<String> = serde_json::from_slice(&iresp.data).unwrap_or_default();
+            let fnames: Vec<String> = fnames
+                .into_iter()
+                .filter(|fname| authorizer.authorize(principal, fname))
+                .collect();
+            SlackerPacket(
+                header,
+                SlackerPacketBody::InspectResponse(SlackerInspectResponsePacket {
+                    data: serde_json::to_vec(&fnames).unwrap_or_default(),
+                }),
+            )
+        }
+        resp => resp,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use addr::SlackerAddr;
+    use metrics::result_code;
+
+    struct Token;
+
+    impl Authenticator for Token {
+        fn authenticate(&self, _: &ConnectionInfo, token: Option<&[u8]>) -> Option<String> {
+            match token {
+                Some(b"secret") => Some("alice".to_owned()),
+                _ => None,
+            }
+        }
+    }
+
+    struct Echo;
+
+    impl Service for Echo {
+        type Request = SlackerPacket;
+        type Response = SlackerPacket;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = SlackerPacket, Error = io::Error>>;
+
+        fn call(&self, req: SlackerPacket) -> Self::Future {
+            let SlackerPacket(header, _) = req;
+            Box::new(ok(error_response(header, RESULT_CODE_SUCCESS)))
+        }
+    }
+
+    struct Functions;
+
+    impl Service for Functions {
+        type Request = SlackerPacket;
+        type Response = SlackerPacket;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = SlackerPacket, Error = io::Error>>;
+
+        fn call(&self, req: SlackerPacket) -> Self::Future {
+            let SlackerPacket(mut header, _) = req;
+            header.packet_type = PACKET_TYPE_INSPECT_RESPONSE;
+            let fnames = vec!["demo/echo", "admin/drop"];
+            Box::new(ok(SlackerPacket(
+                header,
+                SlackerPacketBody::InspectResponse(SlackerInspectResponsePacket {
+                    data: serde_json::to_vec(&fnames).unwrap(),
+                }),
+            )))
+        }
+    }
+
+    fn header(packet_type: u8) -> SlackerPacketHeader {
+        SlackerPacketHeader {
+            version: PROTOCOL_VERSION_6,
+            serial_id: 1,
+            packet_type,
+        }
+    }
+
+    fn inspect(inspect_type: u8, data: &str) -> SlackerPacket {
+        SlackerPacket(
+            header(PACKET_TYPE_INSPECT_REQUEST),
+            SlackerPacketBody::InspectRequest(SlackerInspectRequestPacket {
+                inspect_type,
+                data: data.as_bytes().to_vec(),
+            }),
+        )
+    }
+
+    fn request(fname: &str, token: Option<&str>) -> SlackerPacket {
+        SlackerPacket(
+            header(PACKET_TYPE_REQUEST),
+            SlackerPacketBody::Request(SlackerRequestPacket {
+                content_type: JSON_CONTENT_TYPE,
+                fname: fname.to_owned(),
+                arguments: b"[]".to_vec(),
+                extensions: token
+                    .map(|token| SlackerExtension {
+                        ext_id: EXTENSION_ID_AUTH_TOKEN,
+                        data: token.as_bytes().to_vec(),
+                    })
+                    .into_iter()
+                    .collect(),
+            }),
+        )
+    }
+
+    #[test]
+    fn inspect_requires_authentication_and_acl() {
+        let conn = Arc::new(ConnectionInfo::new(Some(SlackerAddr::Tcp(
+            "127.0.0.1:2104".parse().unwrap(),
+        ))));
+        let acl = Acl::new().allow("alice", "demo/*");
+        let service = Authenticated::new(Echo, conn, Arc::new(Token), Some(Arc::new(acl)));
+        let code = |req| result_code(&service.call(req).wait().unwrap());
+
+        assert_eq!(
+            code(inspect(INSPECT_TYPE_FUNCTIONS, "")),
+            Some(RESULT_CODE_ACL_REJECT)
+        );
+        assert_eq!(
+            code(request("demo/echo", Some("secret"))),
+            Some(RESULT_CODE_SUCCESS)
+        );
+        assert_eq!(
+            code(inspect(INSPECT_TYPE_FUNCTIONS, "")),
+            Some(RESULT_CODE_SUCCESS)
+        );
+        assert_eq!(
+            code(inspect(INSPECT_TYPE_META, "demo/echo")),
+            Some(RESULT_CODE_SUCCESS)
+        );
+        assert_eq!(
+            code(inspect(INSPECT_TYPE_META, "admin/drop")),
+            Some(RESULT_CODE_ACL_REJECT)
+        );
+        assert_eq!(
+            code(request("admin/drop", None)),
+            Some(RESULT_CODE_ACL_REJECT)
+        );
+    }
+
+    #[test]
+    fn function_lists_leave_out_what_the_acl_denies() {
+        let conn = Arc::new(ConnectionInfo::new(Some(SlackerAddr::Tcp(
+            "127.0.0.1:2104".parse().unwrap(),
+        ))));
+        conn.set_principal("alice".to_owned());
+        let acl = Acl::new().allow("alice", "demo/*");
+        let service = Authenticated::new(Functions, conn, Arc::new(Token), Some(Arc::new(acl)));
+
+        match service
+            .call(inspect(INSPECT_TYPE_FUNCTIONS, ""))
+            .wait()
+            .unwrap()
+        {
+            SlackerPacket(_, SlackerPacketBody::InspectResponse(iresp)) => {
+                let fnames: Vec<String> = serde_json::from_slice(&iresp.data).unwrap();
+                assert_eq!(fnames, vec!["demo/echo".to_owned()]);
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+}
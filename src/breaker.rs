@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{err, Either};
+use futures::Future;
+use tcore::reactor::{Handle, Timeout};
+
+use addr::SlackerAddr;
+
+/// The error of calls failed fast because their circuit is open, wrapped in
+/// an `io::Error` of kind `Other`.
+#[derive(Debug, Clone)]
+pub struct CircuitOpen {
+    pub addr: SlackerAddr,
+    /// Set when circuits are tracked per function.
+    pub fname: Option<String>,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.fname {
+            Some(ref fname) => write!(f, "Circuit open for {} on {}.", fname, self.addr),
+            None => write!(f, "Circuit open for {}.", self.addr),
+        }
+    }
+}
+
+impl Error for CircuitOpen {
+    fn description(&self) -> &str {
+        "circuit open"
+    }
+}
+
+impl CircuitOpen {
+    /// The `CircuitOpen` an error of a call wraps, if any.
+    pub fn from_error(e: &io::Error) -> Option<&CircuitOpen> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+/// Lets a call through a circuit, handed back to `CircuitBreaker::record`
+/// with its result.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitPermit {
+    // set for the call probing a half-open circuit, to when it was let through
+    probe: Option<Instant>,
+}
+
+enum Circuit {
+    // results of the latest calls, true for failures
+    Closed(VecDeque<bool>),
+    Open(Instant),
+    // since when a call probes whether the server recovered
+    HalfOpen(Instant),
+}
+
+/// Fails calls fast while their server keeps failing. A circuit opens once
+/// enough of its latest calls failed, and after a while lets one call
+/// through to probe whether the server recovered.
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    window: usize,
+    min_calls: usize,
+    open_for: Duration,
+    call_timeout: Option<Duration>,
+    per_function: bool,
+    circuits: Mutex<BTreeMap<(String, Option<String>), Circuit>>,
+}
+
+impl CircuitBreaker {
+    /// Opens a circuit for `open_for` when `failure_rate`, between 0 and 1,
+    /// of its latest 20 calls failed, once there were at least 10.
+    pub fn new(failure_rate: f64, open_for: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_rate,
+            window: 20,
+            min_calls: 10,
+            open_for,
+            call_timeout: None,
+            per_function: false,
+            circuits: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with_window(mut self, calls: usize, min_calls: usize) -> Self {
+        self.window = calls;
+        self.min_calls = min_calls;
+        self
+    }
+
+    /// Calls taking longer fail with `ErrorKind::TimedOut`, counted as
+    /// failures.
+    pub fn with_call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = Some(timeout);
+        self
+    }
+
+    /// Tracks a circuit per function of each server, rather than one per
+    /// server.
+    pub fn per_function(mut self) -> Self {
+        self.per_function = true;
+        self
+    }
+
+    fn key(&self, addr: &SlackerAddr, fname: &str) -> (String, Option<String>) {
+        (
+            addr.to_string(),
+            if self.per_function {
+                Some(fname.to_owned())
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Whether calls fail fast with `CircuitOpen`, rather than one being let
+    /// through to probe the server.
+    pub fn is_open(&self, addr: &SlackerAddr, fname: &str) -> bool {
+        let now = Instant::now();
+        match self.circuits.lock().unwrap().get(&self.key(addr, fname)) {
+            Some(&Circuit::Open(until)) => now < until,
+            Some(&Circuit::HalfOpen(since)) => now < since + self.open_for,
+            _ => false,
+        }
+    }
+
+    /// Lets a call through, or fails it with `CircuitOpen`.
+    pub fn acquire(&self, addr: &SlackerAddr, fname: &str) -> io::Result<CircuitPermit> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(self.key(addr, fname))
+            .or_insert_with(|| Circuit::Closed(VecDeque::new()));
+        let probe = match *circuit {
+            Circuit::Closed(_) => return Ok(CircuitPermit { probe: None }),
+            Circuit::Open(until) => now >= until,
+            // a probe never completed
+            Circuit::HalfOpen(since) => now >= since + self.open_for,
+        };
+        if probe {
+            debug!("probing {} after the circuit opened", addr);
+            *circuit = Circuit::HalfOpen(now);
+            Ok(CircuitPermit { probe: Some(now) })
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                CircuitOpen {
+                    addr: addr.clone(),
+                    fname: if self.per_function {
+                        Some(fname.to_owned())
+                    } else {
+                        None
+                    },
+                },
+            ))
+        }
+    }
+
+    pub fn record(&self, addr: &SlackerAddr, fname: &str, permit: CircuitPermit, failed: bool) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(self.key(addr, fname))
+            .or_insert_with(|| Circuit::Closed(VecDeque::new()));
+        let open = match *circuit {
+            Circuit::Closed(ref mut results) => {
+                results.push_back(failed);
+                while results.len() > self.window {
+                    results.pop_front();
+                }
+                let failures = results.iter().filter(|f| **f).count();
+                results.len() >= self.min_calls
+                    && failures as f64 >= self.failure_rate * results.len() as f64
+            }
+            // only the probe tells whether the server recovered, not calls
+            // let through before the circuit opened
+            Circuit::HalfOpen(since) if permit.probe == Some(since) => failed,
+            Circuit::HalfOpen(_) | Circuit::Open(_) => return,
+        };
+        if open {
+            warn!("opening the circuit for {}", addr);
+            *circuit = Circuit::Open(Instant::now() + self.open_for);
+        } else if let Circuit::HalfOpen(_) = *circuit {
+            debug!("closing the circuit for {}", addr);
+            *circuit = Circuit::Closed(VecDeque::new());
+        }
+    }
+}
+
+// Only failing to reach the server counts, not errors the server answered
+// with.
+fn is_failure(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Makes the call if its circuit lets it, recording how it went.
+pub fn guard<T, F>(
+    breaker: Arc<CircuitBreaker>,
+    handle: &Handle,
+    addr: SlackerAddr,
+    fname: String,
+    call: F,
+) -> Box<dyn Future<Item = T, Error = io::Error>>
+where
+    T: 'static,
+    F: FnOnce() -> Box<dyn Future<Item = T, Error = io::Error>>,
+{
+    let permit = match breaker.acquire(&addr, &fname) {
+        Ok(permit) => permit,
+        Err(e) => return Box::new(err(e)),
+    };
+    let call = call();
+    let call = match breaker.call_timeout {
+        Some(timeout) => match Timeout::new(timeout, handle) {
+            Ok(timer) => Box::new(call.select2(timer).then(|r| match r {
+                Ok(Either::A((item, _))) => Ok(item),
+                Err(Either::A((e, _))) => Err(e),
+                Ok(Either::B(_)) => Err(io::Error::new(io::ErrorKind::TimedOut, "Call timed out.")),
+                Err(Either::B((e, _))) => Err(e),
+            })),
+            Err(e) => return Box::new(err(e)),
+        },
+        None => call,
+    };
+    Box::new(call.then(move |r| {
+        let failed = matches!(r, Err(ref e) if is_failure(e));
+        breaker.record(&addr, &fname, permit, failed);
+        r
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn addr() -> SlackerAddr {
+        SlackerAddr::Tcp("127.0.0.1:2104".parse().unwrap())
+    }
+
+    fn failing_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(1.0, Duration::from_millis(20)).with_window(2, 2);
+        for _ in 0..2 {
+            let permit = breaker.acquire(&addr(), "demo/f").unwrap();
+            breaker.record(&addr(), "demo/f", permit, true);
+        }
+        breaker
+    }
+
+    #[test]
+    fn only_unreachable_servers_count_as_failures() {
+        assert!(is_failure(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(is_failure(&io::Error::from(io::ErrorKind::BrokenPipe)));
+        assert!(!is_failure(&io::Error::new(
+            io::ErrorKind::Other,
+            "Server busy."
+        )));
+        assert!(!is_failure(&io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    #[test]
+    fn the_probe_closes_the_circuit() {
+        let breaker = failing_breaker();
+        assert!(breaker.is_open(&addr(), "demo/f"));
+        let e = breaker.acquire(&addr(), "demo/f").unwrap_err();
+        assert!(CircuitOpen::from_error(&e).is_some());
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(!breaker.is_open(&addr(), "demo/f"));
+        let probe = breaker.acquire(&addr(), "demo/f").unwrap();
+        assert!(breaker.acquire(&addr(), "demo/f").is_err());
+        breaker.record(&addr(), "demo/f", probe, false);
+        assert!(breaker.acquire(&addr(), "demo/f").is_ok());
+    }
+
+    #[test]
+    fn earlier_calls_do_not_count_as_the_probe() {
+        let breaker = CircuitBreaker::new(1.0, Duration::from_millis(20)).with_window(2, 2);
+        let late = breaker.acquire(&addr(), "demo/f").unwrap();
+        for _ in 0..2 {
+            let permit = breaker.acquire(&addr(), "demo/f").unwrap();
+            breaker.record(&addr(), "demo/f", permit, true);
+        }
+
+        thread::sleep(Duration::from_millis(30));
+        let probe = breaker.acquire(&addr(), "demo/f").unwrap();
+        breaker.record(&addr(), "demo/f", late, false);
+        assert!(breaker.is_open(&addr(), "demo/f"));
+        breaker.record(&addr(), "demo/f", probe, true);
+        assert!(breaker.is_open(&addr(), "demo/f"));
+    }
+}
@@ -1,83 +1,445 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicIsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use futures::future::err;
-use futures::{Future, IntoFuture};
+use futures::future::{err, join_all, Either, Shared};
+use futures::{unsync, Async, Future, IntoFuture, Poll, Stream};
 use tcore::net::TcpStream;
-use tcore::reactor::Core;
-use tproto::multiplex::ClientService;
-use tproto::TcpClient;
+use tcore::reactor::{Core, Handle, Timeout};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tls")]
+use tokio_rustls::webpki::DNSNameRef;
+use tracing::field::Empty;
 use tservice::Service;
+#[cfg(unix)]
+use tuds::UnixStream;
 
 use serde_json::value::Value as Json;
 
+use addr::SlackerAddr;
+use breaker::{guard, CircuitBreaker};
+use duplex::ClientConnection;
+use interceptor::ConnectionInfo;
 use json::*;
+use metrics::{Metered, Metrics};
 use parser::*;
+use registry::FunctionRegistry;
+use retry::{retry, RetryPolicy};
 use serializer::*;
+use service::{BoxService, Boxed, SlackerService};
+#[cfg(feature = "tls")]
+use tls::TlsClientConfig;
+use trace::{Instrumented, TraceContext};
 
+#[derive(Clone)]
 pub struct ClientManager {
     serializer: Arc<JsonSerializer>,
+    auth_token: Option<Arc<Vec<u8>>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    propagate_trace: bool,
+    functions: Option<FunctionRegistry<JsonRpcHandler>>,
+    retry: Option<RetryPolicy>,
+    breaker: Option<Arc<CircuitBreaker>>,
 }
 
 impl ClientManager {
     pub fn new() -> ClientManager {
         let serializer = Arc::new(JsonSerializer);
-        ClientManager { serializer }
+        ClientManager {
+            serializer,
+            auth_token: None,
+            metrics: None,
+            propagate_trace: false,
+            functions: None,
+            retry: None,
+            breaker: None,
+        }
+    }
+
+    /// Clients send the token with every request so servers that require
+    /// authentication accept them.
+    pub fn with_auth_token(mut self, token: Vec<u8>) -> ClientManager {
+        self.auth_token = Some(Arc::new(token));
+        self
+    }
+
+    /// Clients record their calls to `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> ClientManager {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Clients send the trace context of each call to the server, which
+    /// requires a server that understands protocol v6.
+    pub fn with_trace_propagation(mut self) -> ClientManager {
+        self.propagate_trace = true;
+        self
+    }
+
+    /// Clients retry failed calls of functions that are idempotent, as told
+    /// by the server or by calling them with `Client::idempotent_call`.
+    /// Retries go over the same connection, so only timeouts, e.g. the call
+    /// timeout of a circuit breaker, and open circuits are retried, whatever
+    /// else the policy allows; `ClusterClient::with_retry` moves on to the
+    /// next server.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> ClientManager {
+        let retryable = policy
+            .retryable()
+            .iter()
+            .cloned()
+            .filter(|kind| *kind == io::ErrorKind::TimedOut)
+            .collect();
+        self.retry = Some(policy.with_retryable(retryable));
+        self
+    }
+
+    /// Shared by all clients of this manager, so calls to a server fail fast
+    /// with `CircuitOpen` while it keeps failing. `ClusterClient` calls the
+    /// other servers meanwhile.
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> ClientManager {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&Arc<CircuitBreaker>> {
+        self.breaker.as_ref()
+    }
+
+    /// Clients serve `functions` to their server over the same connection.
+    /// Calls of blocking handlers fail, as clients have no thread pool.
+    /// Without functions, requests from the server are answered with
+    /// `RESULT_CODE_NOT_FOUND`.
+    pub fn with_functions(mut self, functions: FunctionRegistry<JsonRpcHandler>) -> ClientManager {
+        self.functions = Some(functions);
+        self
     }
 
     pub fn connect(
         &self,
         core: &mut Core,
         addr: &SocketAddr,
-    ) -> Box<Future<Item = Client, Error = io::Error>> {
-        let handle = core.handle();
-        let serializer = self.serializer.clone();
-        let rt = TcpClient::new(JsonSlacker)
-            .connect(addr, &handle)
-            .map(|client_service| Client {
-                inner: client_service,
-                serial_id_gen: AtomicIsize::new(0),
-                serializer,
-            });
-        Box::new(rt)
+    ) -> Box<dyn Future<Item = Client, Error = io::Error>> {
+        self.connect_with_handle(&core.handle(), addr)
+    }
+
+    pub fn connect_with_handle(
+        &self,
+        handle: &Handle,
+        addr: &SocketAddr,
+    ) -> Box<dyn Future<Item = Client, Error = io::Error>> {
+        self.connect_addr(handle, &SlackerAddr::Tcp(*addr))
     }
+
+    pub fn connect_addr(
+        &self,
+        handle: &Handle,
+        addr: &SlackerAddr,
+    ) -> Box<dyn Future<Item = Client, Error = io::Error>> {
+        let manager = self.clone();
+        let handle = handle.clone();
+        let slacker_addr = addr.clone();
+        match *addr {
+            SlackerAddr::Tcp(ref addr) => Box::new(
+                TcpStream::connect(addr, &handle)
+                    .map(move |stream| manager.bind(&handle, stream, slacker_addr)),
+            ),
+            SlackerAddr::Unix(ref path) => Box::new(
+                connect_unix(path, &handle)
+                    .map(|stream| manager.bind(&handle, stream, slacker_addr))
+                    .into_future(),
+            ),
+        }
+    }
+
+    /// Connects over TLS, verifying the server certificate against `domain`.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(
+        &self,
+        handle: &Handle,
+        addr: &SocketAddr,
+        domain: &str,
+        tls: &TlsClientConfig,
+    ) -> Box<dyn Future<Item = Client, Error = io::Error>> {
+        let domain = match DNSNameRef::try_from_ascii_str(domain) {
+            Ok(domain) => domain.to_owned(),
+            Err(_) => {
+                return Box::new(err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid TLS domain name.",
+                )))
+            }
+        };
+        let manager = self.clone();
+        let slacker_addr = SlackerAddr::Tcp(*addr);
+        let connector = tls.connector().clone();
+        let handle = handle.clone();
+        Box::new(
+            TcpStream::connect(addr, &handle)
+                .and_then(move |stream| connector.connect(domain.as_ref(), stream))
+                .map(move |stream| manager.bind(&handle, stream, slacker_addr)),
+        )
+    }
+
+    fn bind<T>(&self, handle: &Handle, io: T, addr: SlackerAddr) -> Client
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let service = self.functions.clone().map(|functions| {
+            let conn = Arc::new(ConnectionInfo::new(Some(addr.clone())));
+            let service = SlackerService::new(functions, Arc::new(JsonSerializer), conn);
+            Box::new(Boxed(service)) as BoxService
+        });
+        let connection = ClientConnection::bind(handle, io, service, self.metrics.clone());
+        Client {
+            inner: Rc::new(metered(Box::new(connection.clone()), self.metrics.clone())),
+            connection,
+            addr,
+            serial_id_gen: Arc::new(AtomicIsize::new(0)),
+            serializer: self.serializer.clone(),
+            auth_token: self.auth_token.clone(),
+            propagate_trace: self.propagate_trace,
+            handle: handle.clone(),
+            retry: self.retry.clone(),
+            breaker: self.breaker.clone(),
+            idempotent: Rc::new(RefCell::new(BTreeMap::new())),
+        }
+    }
+}
+
+impl Default for ClientManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn metered(inner: BoxService, metrics: Option<Arc<dyn Metrics>>) -> BoxService {
+    match metrics {
+        Some(metrics) => Box::new(Metered::new(inner, metrics)),
+        None => inner,
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path, handle: &Handle) -> io::Result<UnixStream> {
+    UnixStream::connect(path, handle)
 }
 
+#[cfg(not(unix))]
+fn connect_unix(_: &Path, _: &Handle) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unix domain sockets are not supported on this platform.",
+    ))
+}
+
+/// Clones share the same connection.
+#[derive(Clone)]
 pub struct Client {
-    inner: ClientService<TcpStream, JsonSlacker>,
-    serial_id_gen: AtomicIsize,
+    inner: Rc<BoxService>,
+    connection: ClientConnection,
+    addr: SlackerAddr,
+    serial_id_gen: Arc<AtomicIsize>,
     serializer: Arc<JsonSerializer>,
+    auth_token: Option<Arc<Vec<u8>>>,
+    propagate_trace: bool,
+    handle: Handle,
+    retry: Option<RetryPolicy>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    // whether functions are idempotent, as told by the server, shared by
+    // the calls asking at the same time
+    idempotent: Rc<RefCell<BTreeMap<String, Lookup>>>,
 }
 
+type Lookup = Shared<Box<dyn Future<Item = bool, Error = ()>>>;
+
 impl Service for Client {
     type Request = SlackerPacket;
     type Response = SlackerPacket;
     type Error = io::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        Box::new(self.inner.call(req))
+        self.inner.call(req)
     }
 }
 
 impl Client {
+    pub fn addr(&self) -> &SlackerAddr {
+        &self.addr
+    }
+
     pub fn rpc_call(
         &self,
         ns_name: &str,
         fn_name: &str,
         args: Vec<Json>,
-    ) -> Box<Future<Item = Json, Error = io::Error>> {
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
         let mut fname = String::new();
         fname.push_str(ns_name);
-        fname.push_str("/");
+        fname.push('/');
         fname.push_str(fn_name);
 
+        if self.retry.is_none() {
+            return self.call_once(fname, args);
+        }
+        let client = self.clone();
+        Box::new(self.is_idempotent(&fname).and_then(move |idempotent| {
+            if idempotent {
+                client.call_with_retry(fname, args)
+            } else {
+                client.call_once(fname, args)
+            }
+        }))
+    }
+
+    /// Like `rpc_call`, retried as if the function were marked idempotent.
+    pub fn idempotent_call(
+        &self,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        self.call_with_retry(format!("{}/{}", ns_name, fn_name), args)
+    }
+
+    /// Whether the server marked `fname` idempotent, asked once per function.
+    /// Functions are taken as not idempotent when asking fails, which is
+    /// asked again next time.
+    pub fn is_idempotent(&self, fname: &str) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        let lookup = self.idempotent.borrow().get(fname).cloned();
+        let lookup = match lookup {
+            Some(lookup) => lookup,
+            None => {
+                let lookup = self.inspect_idempotent(fname).shared();
+                self.idempotent
+                    .borrow_mut()
+                    .insert(fname.to_owned(), lookup.clone());
+                lookup
+            }
+        };
+        Box::new(lookup.then(|r| Ok(r.map(|idempotent| *idempotent).unwrap_or(false))))
+    }
+
+    // Whether the server already told that `fname` is idempotent.
+    fn known_idempotent(&self, fname: &str) -> bool {
+        let idempotent = self.idempotent.borrow();
+        match idempotent.get(fname).and_then(|lookup| lookup.peek()) {
+            Some(Ok(idempotent)) => *idempotent,
+            _ => false,
+        }
+    }
+
+    fn inspect_idempotent(&self, fname: &str) -> Box<dyn Future<Item = bool, Error = ()>> {
         let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
         let header = SlackerPacketHeader {
             version: PROTOCOL_VERSION_5,
             serial_id: sid,
+            packet_type: PACKET_TYPE_INSPECT_REQUEST,
+        };
+        let body = SlackerPacketBody::InspectRequest(SlackerInspectRequestPacket {
+            inspect_type: INSPECT_TYPE_META,
+            data: fname.as_bytes().to_vec(),
+        });
+        let fname = fname.to_owned();
+        let cache = self.idempotent.clone();
+        let serializer = self.serializer.clone();
+        Box::new(self.call(SlackerPacket(header, body)).then(move |r| {
+            match r {
+                Ok(SlackerPacket(_, SlackerPacketBody::InspectResponse(r))) => Ok(serializer
+                    .deserialize(&r.data)
+                    .ok()
+                    .and_then(|meta: Json| meta["idempotent"].as_bool())
+                    .unwrap_or(false)),
+                r => {
+                    debug!("failed to inspect {}: {:?}", fname, r.err());
+                    cache.borrow_mut().remove(&fname);
+                    Ok(false)
+                }
+            }
+        }))
+    }
+
+    fn call_with_retry(
+        &self,
+        fname: String,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        match self.retry {
+            Some(ref policy) => {
+                let client = self.clone();
+                retry(policy.clone(), self.handle.clone(), move || {
+                    client.call_once(fname.clone(), args.clone())
+                })
+            }
+            None => self.call_once(fname, args),
+        }
+    }
+
+    fn call_once(
+        &self,
+        fname: String,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        match self.breaker {
+            Some(ref breaker) => guard(
+                breaker.clone(),
+                &self.handle,
+                self.addr.clone(),
+                fname.clone(),
+                || self.send_call(fname, args),
+            ),
+            None => self.send_call(fname, args),
+        }
+    }
+
+    // The auth token and, when traces are propagated, the trace context
+    // every request of this client carries.
+    fn extensions(&self, context: Option<&TraceContext>) -> Vec<SlackerExtension> {
+        let mut extensions = Vec::new();
+        if let Some(ref token) = self.auth_token {
+            extensions.push(SlackerExtension {
+                ext_id: EXTENSION_ID_AUTH_TOKEN,
+                data: token.as_ref().clone(),
+            });
+        }
+        if let (true, Some(context)) = (self.propagate_trace, context) {
+            extensions.push(context.extension());
+        }
+        extensions
+    }
+
+    fn send_call(
+        &self,
+        fname: String,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        let context = trace_context();
+        let extensions = self.extensions(Some(&context));
+
+        let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
+        let span = tracing::info_span!(
+            "slacker.call",
+            fname = fname.as_str(),
+            serial_id = sid,
+            peer = %self.addr,
+            trace_id = Empty,
+            span_id = Empty,
+            parent_id = Empty
+        );
+        context.record(&span);
+        let header = SlackerPacketHeader {
+            version: if extensions.is_empty() {
+                PROTOCOL_VERSION_5
+            } else {
+                PROTOCOL_VERSION_6
+            },
+            serial_id: sid,
             packet_type: PACKET_TYPE_REQUEST,
         };
 
@@ -85,30 +447,125 @@ impl Client {
         let body_result = serializer.serialize(&args.into()).map(|serialized_args| {
             SlackerPacketBody::Request(SlackerRequestPacket {
                 content_type: JSON_CONTENT_TYPE,
-                fname: fname,
+                fname,
                 arguments: serialized_args,
+                extensions,
             })
         });
-        match body_result {
-            Ok(body) => Box::new(self.call(SlackerPacket(header, body)).and_then(
-                move |SlackerPacket(_, body)| {
-                    debug!("getting results {:?}", body);
-                    match body {
-                        SlackerPacketBody::Response(r) => {
-                            serializer.deserialize(&r.data).into_future()
+        let call = match body_result {
+            Ok(body) => {
+                self.call(SlackerPacket(header, body))
+                    .and_then(move |SlackerPacket(_, body)| {
+                        debug!("getting results {:?}", body);
+                        match body {
+                            SlackerPacketBody::Response(r) => {
+                                serializer.deserialize(&r.data).into_future()
+                            }
+                            SlackerPacketBody::Error(e) => err(result_code_error(e.result_code)),
+                            _ => err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Unexpect packet.",
+                            )),
                         }
-                        _ => err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Unexpect packet.",
-                        )),
-                    }
-                },
-            )),
-            Err(e) => Box::new(err(e)),
+                    })
+            }
+            Err(e) => return Box::new(err(e)),
+        };
+        Box::new(Instrumented::new(call, span, context))
+    }
+
+    /// Calls a streaming function, receiving its items as they are produced.
+    /// Functions that do not stream yield the items of their result if it is
+    /// a list, or the result itself. Dropping the stream early cancels it on
+    /// the server.
+    pub fn stream_call(
+        &self,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Stream<Item = Json, Error = io::Error>> {
+        let fname = format!("{}/{}", ns_name, fn_name);
+        let extensions = self.extensions(Some(&trace_context()));
+
+        let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
+        let mut open_extensions = extensions.clone();
+        open_extensions.push(stream_extension(STREAM_OPEN, sid));
+        let arguments = match self.serializer.serialize(&args.into()) {
+            Ok(arguments) => arguments,
+            Err(e) => return Box::new(err(e).into_stream()),
+        };
+        // before the request, so no chunk is missed
+        let chunks = self.connection.subscribe(sid);
+        let open = self.call(v6_request(sid, &fname, arguments, open_extensions));
+        Box::new(CallStream {
+            inner: self.inner.clone(),
+            serial_id_gen: self.serial_id_gen.clone(),
+            serializer: self.serializer.clone(),
+            fname,
+            extensions,
+            stream_id: sid,
+            chunks,
+            response: open,
+            items: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// Notifications the server sends to this connection from now on, along
+    /// with those it kept while there was no subscriber. Every such stream
+    /// receives all of them, until it is dropped or the connection is
+    /// closed.
+    pub fn notifications(&self) -> Box<dyn Stream<Item = Json, Error = io::Error>> {
+        let mut extensions = self.extensions(None);
+        extensions.push(SlackerExtension {
+            ext_id: EXTENSION_ID_NOTIFICATIONS,
+            data: Vec::new(),
+        });
+        let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
+        let pushed = self.connection.subscribe(sid);
+        // answered by the notifications the server pushes
+        let request = v6_request(sid, "", b"[]".to_vec(), extensions.clone());
+        if let Err(e) = self.connection.send(request) {
+            return Box::new(err(e).into_stream());
         }
+        Box::new(Notifications {
+            connection: self.connection.clone(),
+            serial_id_gen: self.serial_id_gen.clone(),
+            extensions,
+            subscription: sid,
+            serializer: self.serializer.clone(),
+            pushed,
+            items: VecDeque::new(),
+            closed: false,
+        })
     }
 
-    pub fn ping(&self) -> Box<Future<Item = (), Error = io::Error>> {
+    /// Queues calls to send together with `Batch::send`.
+    pub fn batch<'a>(&'a self) -> Batch<'a> {
+        Batch {
+            client: self,
+            calls: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Sends a one-way call, whose result the server does not send back.
+    /// Requires a server that understands protocol v6.
+    pub fn notify(&self, ns_name: &str, fn_name: &str, args: Vec<Json>) -> io::Result<()> {
+        let mut extensions = self.extensions(Some(&trace_context()));
+        extensions.push(SlackerExtension {
+            ext_id: EXTENSION_ID_ONE_WAY,
+            data: Vec::new(),
+        });
+
+        let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
+        let fname = format!("{}/{}", ns_name, fn_name);
+        let arguments = self.serializer.serialize(&args.into())?;
+        self.connection
+            .send(v6_request(sid, &fname, arguments, extensions))
+    }
+
+    pub fn ping(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
         let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
         let header = SlackerPacketHeader {
             version: PROTOCOL_VERSION_5,
@@ -120,3 +577,438 @@ impl Client {
         Box::new(self.call(SlackerPacket(header, body)).map(|_| ()))
     }
 }
+
+pub struct Batch<'a> {
+    client: &'a Client,
+    calls: Vec<(String, String, Vec<Json>)>,
+    timeout: Option<Duration>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn call(mut self, ns_name: &str, fn_name: &str, args: Vec<Json>) -> Self {
+        self.calls
+            .push((ns_name.to_owned(), fn_name.to_owned(), args));
+        self
+    }
+
+    /// Calls still waiting for their result after `timeout` fail with
+    /// `ErrorKind::TimedOut`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Writes all calls at once and resolves to their results, in the order
+    /// they were queued. Nothing is asked of the server first, so only calls
+    /// of functions already known to be idempotent are retried.
+    pub fn send(self) -> Box<dyn Future<Item = Vec<io::Result<Json>>, Error = io::Error>> {
+        let timer = match self.timeout {
+            Some(timeout) => match Timeout::new(timeout, &self.client.handle) {
+                Ok(timer) => Some(timer.shared()),
+                Err(e) => return Box::new(err(e)),
+            },
+            None => None,
+        };
+        // queued before the connection gets to write, so flushed together
+        let client = self.client;
+        let calls: Vec<_> = self
+            .calls
+            .into_iter()
+            .map(|(ns_name, fn_name, args)| {
+                let fname = format!("{}/{}", ns_name, fn_name);
+                let call = if client.known_idempotent(&fname) {
+                    client.call_with_retry(fname, args)
+                } else {
+                    client.call_once(fname, args)
+                };
+                match timer {
+                    Some(ref timer) => Box::new(call.select2(timer.clone()).then(|r| match r {
+                        Ok(Either::A((result, _))) => Ok(Ok(result)),
+                        Err(Either::A((e, _))) => Ok(Err(e)),
+                        // dropping the call stops waiting for its response
+                        Ok(Either::B(_)) => Ok(Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Batch timed out.",
+                        ))),
+                        Err(Either::B((e, _))) => Ok(Err(io::Error::new(e.kind(), e.to_string()))),
+                    }))
+                        as Box<dyn Future<Item = io::Result<Json>, Error = io::Error>>,
+                    None => Box::new(call.then(Ok)),
+                }
+            })
+            .collect();
+        Box::new(join_all(calls))
+    }
+}
+
+// A child of the current trace, or a new one.
+fn trace_context() -> TraceContext {
+    TraceContext::current().map_or_else(TraceContext::new_root, |c| c.child())
+}
+
+fn result_code_error(result_code: u8) -> io::Error {
+    match result_code {
+        RESULT_CODE_NOT_FOUND => io::Error::new(io::ErrorKind::NotFound, "Function not found."),
+        RESULT_CODE_ACL_REJECT => {
+            io::Error::new(io::ErrorKind::PermissionDenied, "Rejected by server ACL.")
+        }
+        RESULT_CODE_SERVER_BUSY => io::Error::new(io::ErrorKind::Other, "Server busy."),
+        _ => io::Error::new(
+            io::ErrorKind::Other,
+            format!("Server error, result code {}.", result_code),
+        ),
+    }
+}
+
+fn v6_request(
+    sid: i32,
+    fname: &str,
+    arguments: Vec<u8>,
+    extensions: Vec<SlackerExtension>,
+) -> SlackerPacket {
+    let header = SlackerPacketHeader {
+        version: PROTOCOL_VERSION_6,
+        serial_id: sid,
+        packet_type: PACKET_TYPE_REQUEST,
+    };
+    SlackerPacket(
+        header,
+        SlackerPacketBody::Request(SlackerRequestPacket {
+            content_type: JSON_CONTENT_TYPE,
+            fname: fname.to_owned(),
+            arguments,
+            extensions,
+        }),
+    )
+}
+
+// Receives the chunks the server pushes, then the response ending them.
+struct CallStream {
+    inner: Rc<BoxService>,
+    serial_id_gen: Arc<AtomicIsize>,
+    serializer: Arc<JsonSerializer>,
+    fname: String,
+    extensions: Vec<SlackerExtension>,
+    stream_id: i32,
+    chunks: unsync::mpsc::UnboundedReceiver<SlackerPacket>,
+    response: Box<dyn Future<Item = SlackerPacket, Error = io::Error>>,
+    items: VecDeque<Json>,
+    done: bool,
+}
+
+impl CallStream {
+    fn cancel(&self) -> Box<dyn Future<Item = SlackerPacket, Error = io::Error>> {
+        let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
+        let mut extensions = self.extensions.clone();
+        extensions.push(stream_extension(STREAM_CANCEL, self.stream_id));
+        self.inner
+            .call(v6_request(sid, &self.fname, b"[]".to_vec(), extensions))
+    }
+
+    fn receive(&mut self, packet: SlackerPacket) -> io::Result<()> {
+        match packet {
+            SlackerPacket(_, SlackerPacketBody::Response(r)) => {
+                match self.serializer.deserialize(&r.data)? {
+                    Json::Array(items) => self.items.extend(items),
+                    item => self.items.push_back(item),
+                }
+                Ok(())
+            }
+            SlackerPacket(_, SlackerPacketBody::Error(e)) => Err(result_code_error(e.result_code)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpect packet.",
+            )),
+        }
+    }
+}
+
+impl Stream for CallStream {
+    type Item = Json;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Json>, io::Error> {
+        loop {
+            if let Some(item) = self.items.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+            if let Ok(Async::Ready(Some(chunk))) = self.chunks.poll() {
+                self.receive(chunk)?;
+                continue;
+            }
+
+            let packet = match self.response.poll() {
+                Ok(Async::Ready(packet)) => packet,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.done = true;
+                    return Err(e);
+                }
+            };
+            self.done = true;
+            // chunks all arrive ahead of the response
+            while let Ok(Async::Ready(Some(chunk))) = self.chunks.poll() {
+                self.receive(chunk)?;
+            }
+            self.receive(packet)?;
+        }
+    }
+}
+
+impl Drop for CallStream {
+    fn drop(&mut self) {
+        if !self.done {
+            // the response is of no interest
+            drop(self.cancel());
+        }
+    }
+}
+
+struct Notifications {
+    connection: ClientConnection,
+    serial_id_gen: Arc<AtomicIsize>,
+    extensions: Vec<SlackerExtension>,
+    subscription: i32,
+    serializer: Arc<JsonSerializer>,
+    pushed: unsync::mpsc::UnboundedReceiver<SlackerPacket>,
+    items: VecDeque<Json>,
+    closed: bool,
+}
+
+impl Stream for Notifications {
+    type Item = Json;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Json>, io::Error> {
+        loop {
+            if let Some(item) = self.items.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            if self.closed {
+                return Ok(Async::Ready(None));
+            }
+            match self.pushed.poll() {
+                Ok(Async::Ready(Some(SlackerPacket(_, SlackerPacketBody::Response(r))))) => {
+                    match self.serializer.deserialize(&r.data)? {
+                        Json::Array(items) => self.items.extend(items),
+                        item => self.items.push_back(item),
+                    }
+                }
+                Ok(Async::Ready(Some(SlackerPacket(_, SlackerPacketBody::Error(e))))) => {
+                    self.closed = true;
+                    return Err(result_code_error(e.result_code));
+                }
+                Ok(Async::Ready(Some(_))) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpect packet.",
+                    ));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) | Err(()) => {
+                    self.closed = true;
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "Connection closed.",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Notifications {
+    fn drop(&mut self) {
+        if !self.closed {
+            let sid = self.serial_id_gen.fetch_add(1, Ordering::SeqCst) as i32;
+            let mut extensions = self.extensions.clone();
+            extensions.push(stream_extension(STREAM_CANCEL, self.subscription));
+            // the response is of no interest
+            let _ = self
+                .connection
+                .send(v6_request(sid, "", b"[]".to_vec(), extensions));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecs::SlackerCodec;
+    use futures::sync::oneshot;
+    use futures::Sink;
+    use metrics::result_code;
+    use service::RpcHandler;
+    use std::cell::Cell;
+    use tcore::net::TcpListener;
+    use tokio_codec::Decoder;
+
+    fn inspected(idempotent: bool) -> (u8, SlackerPacketBody) {
+        let data = format!("{{\"idempotent\":{}}}", idempotent).into_bytes();
+        (
+            PACKET_TYPE_INSPECT_RESPONSE,
+            SlackerPacketBody::InspectResponse(SlackerInspectResponsePacket { data }),
+        )
+    }
+
+    // Answers calls with null and inspect requests with `answers` in turn,
+    // counting the inspect requests.
+    fn serve(core: &Core, answers: Vec<(u8, SlackerPacketBody)>) -> (SocketAddr, Rc<Cell<usize>>) {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr, &core.handle()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inspects = Rc::new(Cell::new(0));
+        let counted = inspects.clone();
+        let answers = Rc::new(RefCell::new(VecDeque::from(answers)));
+        let handle = core.handle();
+        let accepting = listener.incoming().for_each(move |(socket, _)| {
+            let (sink, stream) = SlackerCodec::new(None).framed(socket).split();
+            let counted = counted.clone();
+            let answers = answers.clone();
+            let responses = stream.map(move |(id, SlackerPacket(mut header, body))| {
+                let body = match body {
+                    SlackerPacketBody::InspectRequest(_) => {
+                        counted.set(counted.get() + 1);
+                        let (packet_type, body) = answers.borrow_mut().pop_front().unwrap();
+                        header.packet_type = packet_type;
+                        body
+                    }
+                    _ => {
+                        header.packet_type = PACKET_TYPE_RESPONSE;
+                        SlackerPacketBody::Response(SlackerResponsePacket {
+                            result_code: RESULT_CODE_SUCCESS,
+                            content_type: JSON_CONTENT_TYPE,
+                            data: b"null".to_vec(),
+                            extensions: Vec::new(),
+                        })
+                    }
+                };
+                (id, SlackerPacket(header, body))
+            });
+            handle.spawn(sink.send_all(responses).then(|_| Ok(())));
+            Ok(())
+        });
+        core.handle().spawn(accepting.map_err(|_| ()));
+        (addr, inspects)
+    }
+
+    #[test]
+    fn calls_share_the_idempotent_lookup() {
+        let mut core = Core::new().unwrap();
+        let (addr, inspects) = serve(&core, vec![inspected(true)]);
+        let connect = ClientManager::new()
+            .with_retry(RetryPolicy::new(3))
+            .connect(&mut core, &addr);
+        let client = core.run(connect).unwrap();
+
+        let calls = join_all(vec![
+            client.rpc_call("test", "f", vec![]),
+            client.rpc_call("test", "f", vec![]),
+        ]);
+        assert_eq!(core.run(calls).unwrap(), vec![Json::Null, Json::Null]);
+        assert!(core.run(client.is_idempotent("test/f")).unwrap());
+        assert_eq!(inspects.get(), 1);
+    }
+
+    #[test]
+    fn failed_lookups_fall_back_to_not_idempotent() {
+        let mut core = Core::new().unwrap();
+        let busy = SlackerPacketBody::Error(SlackerErrorPacket {
+            result_code: RESULT_CODE_SERVER_BUSY,
+        });
+        let (addr, inspects) = serve(&core, vec![(PACKET_TYPE_ERROR, busy), inspected(true)]);
+        let connect = ClientManager::new()
+            .with_retry(RetryPolicy::new(3))
+            .connect(&mut core, &addr);
+        let client = core.run(connect).unwrap();
+
+        let call = client.rpc_call("test", "f", vec![]);
+        assert_eq!(core.run(call).unwrap(), Json::Null);
+        // asked again
+        assert!(core.run(client.is_idempotent("test/f")).unwrap());
+        assert_eq!(inspects.get(), 2);
+    }
+
+    #[test]
+    fn batches_wait_for_no_lookup() {
+        let mut core = Core::new().unwrap();
+        let (addr, inspects) = serve(&core, vec![]);
+        let connect = ClientManager::new()
+            .with_retry(RetryPolicy::new(3))
+            .connect(&mut core, &addr);
+        let client = core.run(connect).unwrap();
+
+        let batch = client
+            .batch()
+            .call("test", "f", vec![])
+            .call("test", "g", vec![])
+            .send();
+        let results: Vec<Json> = core
+            .run(batch)
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, vec![Json::Null, Json::Null]);
+        assert_eq!(inspects.get(), 0);
+    }
+
+    #[test]
+    fn clients_retry_no_connection_errors() {
+        let manager = ClientManager::new().with_retry(RetryPolicy::new(3));
+        let policy = manager.retry.unwrap();
+        assert!(policy.is_retryable(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(!policy.is_retryable(&io::Error::from(io::ErrorKind::BrokenPipe)));
+    }
+
+    #[test]
+    fn clients_answer_requests_from_their_server() {
+        let mut core = Core::new().unwrap();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr, &core.handle()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let functions = FunctionRegistry::new();
+        let echo: JsonRpcHandler = RpcHandler::Async(Box::new(|args: &Vec<Json>| {
+            let (tx, rx) = oneshot::channel();
+            tx.send(Json::Array(args.clone())).unwrap();
+            rx
+        }));
+        functions.register("peer/echo", echo);
+        let connect = ClientManager::new()
+            .with_functions(functions)
+            .connect(&mut core, &addr);
+        let accept = listener
+            .incoming()
+            .into_future()
+            .map(|(socket, _)| socket.unwrap().0)
+            .map_err(|(e, _)| e);
+        let (socket, _client) = core.run(accept.join(connect)).unwrap();
+        let (sink, stream) = SlackerCodec::new(None).framed(socket).split();
+
+        let requests = vec![
+            (1, v6_request(1, "peer/echo", b"[1]".to_vec(), Vec::new())),
+            (2, v6_request(2, "peer/missing", b"[]".to_vec(), Vec::new())),
+        ];
+        let sink = core
+            .run(sink.send_all(::futures::stream::iter_ok::<_, io::Error>(requests)))
+            .unwrap();
+        let responses = core.run(stream.take(2).collect()).unwrap();
+        drop(sink);
+
+        let mut responses: Vec<SlackerPacket> =
+            responses.into_iter().map(|(_, packet)| packet).collect();
+        responses.sort_by_key(|packet| packet.0.serial_id);
+        match responses[0] {
+            SlackerPacket(header, SlackerPacketBody::Response(ref r)) => {
+                assert_eq!(header.packet_type, PACKET_TYPE_RESPONSE);
+                let result: Json = serde_json::from_slice(&r.data).unwrap();
+                assert_eq!(result, Json::Array(vec![Json::from(1)]));
+            }
+            ref packet => panic!("unexpected packet {:?}", packet),
+        }
+        assert_eq!(result_code(&responses[1]), Some(RESULT_CODE_NOT_FOUND));
+    }
+}
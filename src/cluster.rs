@@ -0,0 +1,853 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{err, ok};
+use futures::Future;
+use rand::{thread_rng, Rng};
+use tcore::reactor::Handle;
+
+use serde_json::value::Value as Json;
+
+use addr::SlackerAddr;
+use client::{Client, ClientManager};
+use group::{GroupCall, GroupMode, GroupResults};
+use retry::{retry, RetryPolicy};
+
+pub const ZK_ROOT: &str = "/slacker/cluster";
+
+// between reloads of the servers of file and ZooKeeper discovery
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub trait Discovery: Send + Sync + 'static {
+    fn lookup(&self, ns_name: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+pub trait ClusterRegistry: Send + Sync + 'static {
+    fn register(&self, addr: &SocketAddr, namespaces: &[String]) -> io::Result<()>;
+
+    fn unregister(&self, addr: &SocketAddr, namespaces: &[String]) -> io::Result<()>;
+}
+
+/// Namespaces exposed by a function map, taken from the part of each key
+/// before `/`.
+pub fn namespaces<'a, I>(fnames: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    fnames
+        .into_iter()
+        .filter_map(|fname| fname.find('/').map(|i| fname[..i].to_owned()))
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect()
+}
+
+/// Keeps a server registered until dropped.
+pub struct Announcement {
+    registry: Arc<dyn ClusterRegistry>,
+    addr: SocketAddr,
+    namespaces: Vec<String>,
+}
+
+impl Announcement {
+    pub fn new(
+        registry: Arc<dyn ClusterRegistry>,
+        addr: SocketAddr,
+        namespaces: Vec<String>,
+    ) -> io::Result<Announcement> {
+        registry.register(&addr, &namespaces)?;
+        info!("announced {} with namespaces {:?}", addr, namespaces);
+        Ok(Announcement {
+            registry,
+            addr,
+            namespaces,
+        })
+    }
+
+    /// Registers namespaces added since the last update and unregisters the
+    /// removed ones.
+    pub fn update(&mut self, namespaces: Vec<String>) -> io::Result<()> {
+        if namespaces == self.namespaces {
+            return Ok(());
+        }
+        let added: Vec<String> = namespaces
+            .iter()
+            .filter(|ns| !self.namespaces.contains(ns))
+            .cloned()
+            .collect();
+        let removed: Vec<String> = self
+            .namespaces
+            .iter()
+            .filter(|ns| !namespaces.contains(ns))
+            .cloned()
+            .collect();
+        if !added.is_empty() {
+            self.registry.register(&self.addr, &added)?;
+        }
+        if !removed.is_empty() {
+            self.registry.unregister(&self.addr, &removed)?;
+        }
+        info!("announced {} with namespaces {:?}", self.addr, namespaces);
+        self.namespaces = namespaces;
+        Ok(())
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        if let Err(e) = self.registry.unregister(&self.addr, &self.namespaces) {
+            warn!("failed to unregister {}: {}", self.addr, e);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryRegistry {
+    namespaces: Arc<RwLock<BTreeMap<String, BTreeSet<SocketAddr>>>>,
+}
+
+impl MemoryRegistry {
+    pub fn new() -> MemoryRegistry {
+        MemoryRegistry::default()
+    }
+}
+
+impl ClusterRegistry for MemoryRegistry {
+    fn register(&self, addr: &SocketAddr, namespaces: &[String]) -> io::Result<()> {
+        let mut registered = self.namespaces.write().unwrap();
+        for ns_name in namespaces {
+            registered.entry(ns_name.clone()).or_default().insert(*addr);
+        }
+        Ok(())
+    }
+
+    fn unregister(&self, addr: &SocketAddr, namespaces: &[String]) -> io::Result<()> {
+        let mut registered = self.namespaces.write().unwrap();
+        for ns_name in namespaces {
+            let now_empty = match registered.get_mut(ns_name) {
+                Some(servers) => {
+                    servers.remove(addr);
+                    servers.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                registered.remove(ns_name);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Discovery for MemoryRegistry {
+    fn lookup(&self, ns_name: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(self
+            .namespaces
+            .read()
+            .unwrap()
+            .get(ns_name)
+            .map(|servers| servers.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+pub struct StaticDiscovery {
+    servers: Vec<SocketAddr>,
+    namespaces: BTreeMap<String, Vec<SocketAddr>>,
+}
+
+impl StaticDiscovery {
+    /// Every server is assumed to expose every namespace.
+    pub fn new(servers: Vec<SocketAddr>) -> StaticDiscovery {
+        StaticDiscovery {
+            servers,
+            namespaces: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_namespaces(namespaces: BTreeMap<String, Vec<SocketAddr>>) -> StaticDiscovery {
+        StaticDiscovery {
+            servers: Vec::new(),
+            namespaces,
+        }
+    }
+}
+
+impl Discovery for StaticDiscovery {
+    fn lookup(&self, ns_name: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(self
+            .namespaces
+            .get(ns_name)
+            .unwrap_or(&self.servers)
+            .clone())
+    }
+}
+
+// Runs `refresh` every `interval` on a thread of its own, until dropped.
+struct Refresher(mpsc::Sender<()>);
+
+impl Refresher {
+    fn spawn<F: FnMut() + Send + 'static>(interval: Duration, mut refresh: F) -> Refresher {
+        let (stop, stopped) = mpsc::channel();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                refresh();
+            }
+        });
+        Refresher(stop)
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// Reads `namespace host:port` lines from a file when created and again every
+/// 5 seconds, on a thread of its own so lookups never touch the file. Blank
+/// lines and lines starting with `#` are ignored.
+pub struct FileDiscovery {
+    namespaces: Arc<RwLock<BTreeMap<String, Vec<SocketAddr>>>>,
+    _refresher: Refresher,
+}
+
+impl FileDiscovery {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileDiscovery {
+        FileDiscovery::with_interval(path, REFRESH_INTERVAL)
+    }
+
+    pub fn with_interval<P: Into<PathBuf>>(path: P, interval: Duration) -> FileDiscovery {
+        let path = path.into();
+        let namespaces = Arc::new(RwLock::new(BTreeMap::new()));
+        let mut loaded = String::new();
+        let reloaded = namespaces.clone();
+        let mut reload = move || FileDiscovery::reload(&path, &mut loaded, &reloaded);
+        reload();
+        FileDiscovery {
+            namespaces,
+            _refresher: Refresher::spawn(interval, reload),
+        }
+    }
+
+    // Parses the file again if its contents changed, keeping the servers read
+    // before if that fails.
+    fn reload(
+        path: &Path,
+        loaded: &mut String,
+        namespaces: &RwLock<BTreeMap<String, Vec<SocketAddr>>>,
+    ) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("failed to read discovery file {:?}: {}", path, e);
+                return;
+            }
+        };
+        if contents == *loaded {
+            return;
+        }
+        debug!("reloading discovery file {:?}", path);
+        match FileDiscovery::parse(&contents) {
+            Ok(servers) => {
+                *namespaces.write().unwrap() = servers;
+                *loaded = contents;
+            }
+            Err(e) => warn!("failed to parse discovery file {:?}: {}", path, e),
+        }
+    }
+
+    fn parse(contents: &str) -> io::Result<BTreeMap<String, Vec<SocketAddr>>> {
+        let mut namespaces = BTreeMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(ns_name), Some(addr)) => {
+                    let addr = addr
+                        .parse()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    namespaces
+                        .entry(ns_name.to_owned())
+                        .or_insert_with(Vec::new)
+                        .push(addr);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid discovery entry: {}", line),
+                    ))
+                }
+            }
+        }
+        Ok(namespaces)
+    }
+}
+
+impl Discovery for FileDiscovery {
+    fn lookup(&self, ns_name: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(self
+            .namespaces
+            .read()
+            .unwrap()
+            .get(ns_name)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// The subset of a ZooKeeper client needed to follow the node layout used by
+/// Clojure Slacker clusters.
+pub trait ZkClient: Send + Sync + 'static {
+    fn get_children(&self, path: &str) -> io::Result<Vec<String>>;
+
+    /// Creates an ephemeral node, creating missing parents as persistent
+    /// nodes.
+    fn create_ephemeral(&self, path: &str) -> io::Result<()>;
+
+    fn delete(&self, path: &str) -> io::Result<()>;
+}
+
+/// Looks up `/slacker/cluster/<cluster>/namespaces/<ns>/<host:port>` nodes,
+/// read when created and again every 5 seconds on a thread of its own, so
+/// lookups never wait for ZooKeeper.
+pub struct ZkDiscovery<Z: ZkClient> {
+    namespaces: Arc<RwLock<BTreeMap<String, Vec<SocketAddr>>>>,
+    _refresher: Refresher,
+    _zk: PhantomData<Z>,
+}
+
+impl<Z: ZkClient> ZkDiscovery<Z> {
+    pub fn new(zk: Z, cluster: &str) -> ZkDiscovery<Z> {
+        ZkDiscovery::with_interval(zk, cluster, REFRESH_INTERVAL)
+    }
+
+    pub fn with_interval(zk: Z, cluster: &str, interval: Duration) -> ZkDiscovery<Z> {
+        let root = format!("{}/{}/namespaces", ZK_ROOT, cluster);
+        let namespaces = Arc::new(RwLock::new(BTreeMap::new()));
+        let reloaded = namespaces.clone();
+        let reload = move || match ZkDiscovery::load(&zk, &root) {
+            Ok(servers) => *reloaded.write().unwrap() = servers,
+            Err(e) => warn!("failed to read servers under {}: {}", root, e),
+        };
+        reload();
+        ZkDiscovery {
+            namespaces,
+            _refresher: Refresher::spawn(interval, reload),
+            _zk: PhantomData,
+        }
+    }
+
+    fn load(zk: &Z, root: &str) -> io::Result<BTreeMap<String, Vec<SocketAddr>>> {
+        let mut namespaces = BTreeMap::new();
+        for ns_name in zk.get_children(root)? {
+            let path = format!("{}/{}", root, ns_name);
+            let servers = zk
+                .get_children(&path)?
+                .iter()
+                .filter_map(|node| match node.parse() {
+                    Ok(addr) => Some(addr),
+                    Err(_) => {
+                        warn!("ignoring invalid server node {:?} under {}", node, path);
+                        None
+                    }
+                })
+                .collect();
+            namespaces.insert(ns_name, servers);
+        }
+        Ok(namespaces)
+    }
+}
+
+impl<Z: ZkClient> Discovery for ZkDiscovery<Z> {
+    fn lookup(&self, ns_name: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(self
+            .namespaces
+            .read()
+            .unwrap()
+            .get(ns_name)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Registers `/slacker/cluster/<cluster>/servers/<host:port>` and one
+/// `/slacker/cluster/<cluster>/namespaces/<ns>/<host:port>` node per
+/// namespace, as Clojure Slacker servers do.
+pub struct ZkRegistry<Z: ZkClient> {
+    zk: Z,
+    cluster: String,
+}
+
+impl<Z: ZkClient> ZkRegistry<Z> {
+    pub fn new(zk: Z, cluster: &str) -> ZkRegistry<Z> {
+        ZkRegistry {
+            zk,
+            cluster: cluster.to_owned(),
+        }
+    }
+
+    fn paths(&self, addr: &SocketAddr, namespaces: &[String]) -> Vec<String> {
+        let mut paths = vec![format!("{}/{}/servers/{}", ZK_ROOT, self.cluster, addr)];
+        paths.extend(namespaces.iter().map(|ns_name| {
+            format!(
+                "{}/{}/namespaces/{}/{}",
+                ZK_ROOT, self.cluster, ns_name, addr
+            )
+        }));
+        paths
+    }
+}
+
+impl<Z: ZkClient> ClusterRegistry for ZkRegistry<Z> {
+    fn register(&self, addr: &SocketAddr, namespaces: &[String]) -> io::Result<()> {
+        for path in self.paths(addr, namespaces) {
+            debug!("creating zookeeper node {}", path);
+            self.zk.create_ephemeral(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every node it can, returning the first error.
+    fn unregister(&self, addr: &SocketAddr, namespaces: &[String]) -> io::Result<()> {
+        let mut result = Ok(());
+        for path in self.paths(addr, namespaces) {
+            debug!("deleting zookeeper node {}", path);
+            if let Err(e) = self.zk.delete(&path) {
+                warn!("failed to delete zookeeper node {}: {}", path, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadBalance {
+    RoundRobin,
+    Random,
+    LeastPending,
+}
+
+struct Member {
+    client: Client,
+    pending: Cell<usize>,
+}
+
+// Connects to members on demand and drops those whose connection failed.
+#[derive(Clone)]
+struct Members {
+    manager: ClientManager,
+    handle: Handle,
+    members: Rc<RefCell<BTreeMap<SocketAddr, Rc<Member>>>>,
+}
+
+impl Members {
+    fn member(&self, addr: SocketAddr) -> Box<dyn Future<Item = Rc<Member>, Error = io::Error>> {
+        if let Some(m) = self.members.borrow().get(&addr) {
+            return Box::new(ok(m.clone()));
+        }
+
+        debug!("connecting to cluster member {}", addr);
+        let members = self.members.clone();
+        Box::new(
+            self.manager
+                .connect_with_handle(&self.handle, &addr)
+                .map(move |client| {
+                    let member = Rc::new(Member {
+                        client,
+                        pending: Cell::new(0),
+                    });
+                    members.borrow_mut().entry(addr).or_insert(member).clone()
+                }),
+        )
+    }
+
+    fn call_member(
+        &self,
+        addr: SocketAddr,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        let ns_name = ns_name.to_owned();
+        let fn_name = fn_name.to_owned();
+        let members = self.members.clone();
+        Box::new(self.member(addr).and_then(move |m| {
+            m.pending.set(m.pending.get() + 1);
+            m.client.rpc_call(&ns_name, &fn_name, args).then(move |r| {
+                m.pending.set(m.pending.get() - 1);
+                if let Err(ref e) = r {
+                    if is_connection_error(e) {
+                        debug!("dropping cluster member {}: {}", addr, e);
+                        members.borrow_mut().remove(&addr);
+                    }
+                }
+                r
+            })
+        }))
+    }
+
+    // Each attempt goes to the server after the one tried before.
+    fn call_with_retry(
+        &self,
+        policy: RetryPolicy,
+        addrs: Vec<SocketAddr>,
+        first: usize,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        let members = self.clone();
+        let ns_name = ns_name.to_owned();
+        let fn_name = fn_name.to_owned();
+        let attempt = Cell::new(0);
+        retry(policy, self.handle.clone(), move || {
+            let addr = addrs[(first + attempt.get()) % addrs.len()];
+            attempt.set(attempt.get() + 1);
+            members.call_member(addr, &ns_name, &fn_name, args.clone())
+        })
+    }
+}
+
+pub struct ClusterClient {
+    members: Members,
+    discovery: Arc<dyn Discovery>,
+    load_balance: LoadBalance,
+    next: Cell<usize>,
+    retry: Option<RetryPolicy>,
+}
+
+impl ClusterClient {
+    pub fn new(
+        manager: ClientManager,
+        handle: Handle,
+        discovery: Arc<dyn Discovery>,
+        load_balance: LoadBalance,
+    ) -> ClusterClient {
+        ClusterClient {
+            members: Members {
+                manager,
+                handle,
+                members: Rc::new(RefCell::new(BTreeMap::new())),
+            },
+            discovery,
+            load_balance,
+            next: Cell::new(0),
+            retry: None,
+        }
+    }
+
+    /// Retries failed calls of idempotent functions on the next server
+    /// exposing the namespace.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    fn select(&self, addrs: &[SocketAddr]) -> usize {
+        match self.load_balance {
+            LoadBalance::RoundRobin => {
+                let i = self.next.get();
+                self.next.set(i.wrapping_add(1));
+                i % addrs.len()
+            }
+            LoadBalance::Random => thread_rng().gen_range(0, addrs.len()),
+            LoadBalance::LeastPending => {
+                let members = self.members.members.borrow();
+                (0..addrs.len())
+                    .min_by_key(|i| {
+                        members
+                            .get(&addrs[*i])
+                            .map(|m| m.pending.get())
+                            .unwrap_or(0)
+                    })
+                    .unwrap()
+            }
+        }
+    }
+
+    fn lookup(&self, ns_name: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.discovery.lookup(ns_name)?;
+        if addrs.is_empty() {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No server available for namespace {}", ns_name),
+            ))
+        } else {
+            Ok(addrs)
+        }
+    }
+
+    // Leaves out the servers whose circuit is open for the function, unless
+    // all of them are.
+    fn available(&self, addrs: Vec<SocketAddr>, ns_name: &str, fn_name: &str) -> Vec<SocketAddr> {
+        let breaker = match self.members.manager.circuit_breaker() {
+            Some(breaker) => breaker,
+            None => return addrs,
+        };
+        let fname = format!("{}/{}", ns_name, fn_name);
+        let closed: Vec<SocketAddr> = addrs
+            .iter()
+            .filter(|addr| !breaker.is_open(&SlackerAddr::Tcp(**addr), &fname))
+            .cloned()
+            .collect();
+        if closed.is_empty() {
+            addrs
+        } else {
+            closed
+        }
+    }
+
+    fn call_member(
+        &self,
+        addr: SocketAddr,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        self.members.call_member(addr, ns_name, fn_name, args)
+    }
+
+    /// Like `rpc_call`, retried as if the function were marked idempotent.
+    pub fn idempotent_call(
+        &self,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        let addrs = self
+            .lookup(ns_name)
+            .map(|addrs| self.available(addrs, ns_name, fn_name));
+        match (addrs, self.retry.clone()) {
+            (Ok(addrs), Some(policy)) => {
+                let first = self.select(&addrs);
+                self.members
+                    .call_with_retry(policy, addrs, first, ns_name, fn_name, args)
+            }
+            (Ok(addrs), None) => {
+                let addr = addrs[self.select(&addrs)];
+                self.call_member(addr, ns_name, fn_name, args)
+            }
+            (Err(e), _) => Box::new(err(e)),
+        }
+    }
+
+    pub fn rpc_call(
+        &self,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+    ) -> Box<dyn Future<Item = Json, Error = io::Error>> {
+        let addrs = match self.lookup(ns_name) {
+            Ok(addrs) => self.available(addrs, ns_name, fn_name),
+            Err(e) => return Box::new(err(e)),
+        };
+        let first = self.select(&addrs);
+        let policy = match self.retry {
+            Some(ref policy) => policy.clone(),
+            None => return self.call_member(addrs[first], ns_name, fn_name, args),
+        };
+
+        // asks the selected server whether the function is idempotent
+        let fname = format!("{}/{}", ns_name, fn_name);
+        let members = self.members.clone();
+        let ns_name = ns_name.to_owned();
+        let fn_name = fn_name.to_owned();
+        Box::new(
+            self.members
+                .member(addrs[first])
+                .and_then(move |m| m.client.is_idempotent(&fname))
+                .then(
+                    move |idempotent| -> Box<dyn Future<Item = Json, Error = io::Error>> {
+                        if idempotent.unwrap_or(false) {
+                            members.call_with_retry(policy, addrs, first, &ns_name, &fn_name, args)
+                        } else {
+                            members.call_member(addrs[first], &ns_name, &fn_name, args)
+                        }
+                    },
+                ),
+        )
+    }
+
+    /// Calls the function on every server exposing the namespace.
+    pub fn broadcast(
+        &self,
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+        mode: GroupMode,
+    ) -> Box<dyn Future<Item = GroupResults, Error = io::Error>> {
+        match self.lookup(ns_name) {
+            Ok(addrs) => Box::new(self.call_servers(&addrs, ns_name, fn_name, args, mode)),
+            Err(e) => Box::new(err(e)),
+        }
+    }
+
+    pub fn call_servers(
+        &self,
+        addrs: &[SocketAddr],
+        ns_name: &str,
+        fn_name: &str,
+        args: Vec<Json>,
+        mode: GroupMode,
+    ) -> GroupCall {
+        let calls = addrs
+            .iter()
+            .map(|addr| {
+                (
+                    SlackerAddr::Tcp(*addr),
+                    self.call_member(*addr, ns_name, fn_name, args.clone()),
+                )
+            })
+            .collect();
+        GroupCall::new(calls, mode)
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    #[test]
+    fn announcement_follows_namespaces() {
+        let registry = Arc::new(MemoryRegistry::new());
+        let addr: SocketAddr = "127.0.0.1:2104".parse().unwrap();
+        let mut announcement =
+            Announcement::new(registry.clone(), addr, vec!["a".to_owned()]).unwrap();
+        assert_eq!(registry.lookup("a").unwrap(), vec![addr]);
+
+        announcement.update(vec!["b".to_owned()]).unwrap();
+        assert!(registry.lookup("a").unwrap().is_empty());
+        assert_eq!(registry.lookup("b").unwrap(), vec![addr]);
+
+        drop(announcement);
+        assert!(registry.lookup("b").unwrap().is_empty());
+    }
+
+    struct FailingZk(Mutex<Vec<String>>);
+
+    impl ZkClient for FailingZk {
+        fn get_children(&self, _: &str) -> io::Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+
+        fn create_ephemeral(&self, _: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn delete(&self, path: &str) -> io::Result<()> {
+            self.0.lock().unwrap().push(path.to_owned());
+            Err(io::Error::new(io::ErrorKind::NotFound, path.to_owned()))
+        }
+    }
+
+    #[test]
+    fn zk_unregister_deletes_past_errors() {
+        let registry = ZkRegistry::new(FailingZk(Mutex::new(Vec::new())), "demo");
+        let addr: SocketAddr = "127.0.0.1:2104".parse().unwrap();
+        let namespaces = vec!["a".to_owned(), "b".to_owned()];
+        let e = registry.unregister(&addr, &namespaces).unwrap_err();
+        assert!(e.to_string().ends_with("/servers/127.0.0.1:2104"));
+        assert_eq!(registry.zk.0.lock().unwrap().len(), 3);
+    }
+
+    // Waits for a refresh to show `expected`.
+    fn refreshed(discovery: &dyn Discovery, ns_name: &str, expected: &str) {
+        let expected: Vec<SocketAddr> = vec![expected.parse().unwrap()];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while discovery.lookup(ns_name).unwrap() != expected {
+            assert!(Instant::now() < deadline, "not refreshed in time");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn file_discovery_reloads_changed_contents() {
+        let path = env::temp_dir().join(format!("slacker-discovery-{}", ::std::process::id()));
+        fs::write(&path, "demo 127.0.0.1:2104\n").unwrap();
+        let discovery = FileDiscovery::with_interval(path.clone(), Duration::from_millis(10));
+        let first = discovery.lookup("demo").unwrap();
+        assert_eq!(first, vec!["127.0.0.1:2104".parse().unwrap()]);
+
+        // same length, same second: only the contents tell the change
+        fs::write(&path, "demo 127.0.0.1:2105\n").unwrap();
+        refreshed(&discovery, "demo", "127.0.0.1:2105");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    struct MemoryZk(Arc<Mutex<BTreeMap<String, Vec<String>>>>);
+
+    impl ZkClient for MemoryZk {
+        fn get_children(&self, path: &str) -> io::Result<Vec<String>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(path)
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        fn create_ephemeral(&self, _: &str) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn delete(&self, _: &str) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zk_discovery_follows_the_nodes() {
+        let nodes = Arc::new(Mutex::new(BTreeMap::new()));
+        let set = |path: &str, children: &[&str]| {
+            let children = children.iter().map(|c| (*c).to_owned()).collect();
+            nodes.lock().unwrap().insert(path.to_owned(), children);
+        };
+        set("/slacker/cluster/demo/namespaces", &["a"]);
+        set("/slacker/cluster/demo/namespaces/a", &["127.0.0.1:2104"]);
+        let discovery =
+            ZkDiscovery::with_interval(MemoryZk(nodes.clone()), "demo", Duration::from_millis(10));
+        assert_eq!(
+            discovery.lookup("a").unwrap(),
+            vec!["127.0.0.1:2104".parse().unwrap()]
+        );
+        assert!(discovery.lookup("b").unwrap().is_empty());
+
+        set("/slacker/cluster/demo/namespaces", &["a", "b"]);
+        set("/slacker/cluster/demo/namespaces/b", &["127.0.0.1:2105"]);
+        refreshed(&discovery, "b", "127.0.0.1:2105");
+    }
+}
@@ -1,21 +1,33 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use bytes::{BufMut, BytesMut, Writer};
-use nom::{IResult, Offset};
+use nom::Offset;
 use tokio_codec::{Decoder, Encoder};
 
 use std::io::{self, ErrorKind, Write};
+use std::sync::Arc;
 
+use metrics::Metrics;
 //use packets::*;
 use parser::*;
+use tproto::multiplex::RequestId;
 
-#[derive(Copy, Clone)]
-pub struct SlackerCodec;
+/// Reports the bytes it decodes and encodes to `metrics`, if any.
+#[derive(Clone, Default)]
+pub struct SlackerCodec {
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl SlackerCodec {
+    pub fn new(metrics: Option<Arc<dyn Metrics>>) -> SlackerCodec {
+        SlackerCodec { metrics }
+    }
+}
 
 fn write_bytes(cur: &mut Writer<&mut BytesMut>, v: &[u8], prefix_len: usize) -> io::Result<()> {
     if prefix_len == 2 {
-        try!(cur.write_u16::<BigEndian>(v.len() as u16));
+        cur.write_u16::<BigEndian>(v.len() as u16)?;
     } else {
-        try!(cur.write_u32::<BigEndian>(v.len() as u32));
+        cur.write_u32::<BigEndian>(v.len() as u32)?;
     }
 
     cur.write_all(v)
@@ -25,43 +37,85 @@ fn write_string(cur: &mut Writer<&mut BytesMut>, v: &str, prefix_len: usize) ->
     write_bytes(cur, v.as_bytes(), prefix_len)
 }
 
+fn write_extensions(
+    cur: &mut Writer<&mut BytesMut>,
+    version: u8,
+    extensions: &[SlackerExtension],
+) -> io::Result<()> {
+    if version < PROTOCOL_VERSION_6 {
+        return Ok(());
+    }
+    // counted in a byte
+    if extensions.len() > 255 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Too many extensions.",
+        ));
+    }
+
+    cur.write_u8(extensions.len() as u8)?;
+    for ext in extensions {
+        cur.write_i16::<BigEndian>(ext.ext_id)?;
+        write_bytes(cur, &ext.data, 4)?;
+    }
+    Ok(())
+}
+
+fn write_packet(buf: &mut Writer<&mut BytesMut>, packet: &SlackerPacket) -> io::Result<()> {
+    let SlackerPacket(ref header, ref body) = *packet;
+    buf.write_u8(header.version)?;
+    buf.write_i32::<BigEndian>(header.serial_id)?;
+    buf.write_u8(header.packet_type)?;
+
+    match *body {
+        SlackerPacketBody::Request(ref req) => {
+            buf.write_u8(req.content_type)?;
+            write_string(buf, &req.fname, 2)?;
+            write_bytes(buf, &req.arguments, 4)?;
+            write_extensions(buf, header.version, &req.extensions)?;
+        }
+        SlackerPacketBody::Response(ref resp) => {
+            buf.write_u8(resp.content_type)?;
+            buf.write_u8(resp.result_code)?;
+            write_bytes(buf, &resp.data, 4)?;
+            write_extensions(buf, header.version, &resp.extensions)?;
+        }
+        SlackerPacketBody::Error(ref resp) => {
+            buf.write_u8(resp.result_code)?;
+        }
+        SlackerPacketBody::Ping | SlackerPacketBody::Pong => {}
+        SlackerPacketBody::InspectRequest(ref req) => {
+            buf.write_u8(req.inspect_type)?;
+            write_bytes(buf, &req.data, 2)?;
+        }
+        SlackerPacketBody::InspectResponse(ref resp) => {
+            write_bytes(buf, &resp.data, 2)?;
+        }
+        SlackerPacketBody::Interrupt(ref req) => {
+            buf.write_i32::<BigEndian>(req.req_id)?;
+        }
+    }
+    Ok(())
+}
+
 impl Encoder for SlackerCodec {
     type Item = (RequestId, SlackerPacket);
     type Error = io::Error;
 
-    fn encode<'a>(&mut self, frame_in: Self::Item, buf0: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode<'a>(&mut self, frame_in: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
         debug!("writing: {:?}", frame_in);
-        let (_, SlackerPacket(header, body)) = frame_in;
-        let mut buf = buf0.writer();
-        try!(buf.write_u8(header.version));
-        try!(buf.write_i32::<BigEndian>(header.serial_id));
-        try!(buf.write_u8(header.packet_type));
-
-        match body {
-            SlackerPacketBody::Request(ref req) => {
-                try!(buf.write_u8(req.content_type));
-                try!(write_string(&mut buf, &req.fname, 2));
-                try!(write_bytes(&mut buf, &req.arguments, 4));
-            }
-            SlackerPacketBody::Response(ref resp) => {
-                try!(buf.write_u8(resp.content_type));
-                try!(buf.write_u8(resp.result_code));
-                try!(write_bytes(&mut buf, &resp.data, 4));
-            }
-            SlackerPacketBody::Error(ref resp) => {
-                try!(buf.write_u8(resp.result_code));
-            }
-            SlackerPacketBody::Ping | SlackerPacketBody::Pong => {}
-            SlackerPacketBody::InspectRequest(ref req) => {
-                try!(buf.write_u8(req.inspect_type));
-                try!(write_bytes(&mut buf, &req.data, 2));
-            }
-            SlackerPacketBody::InspectResponse(ref resp) => {
-                try!(write_bytes(&mut buf, &resp.data, 2));
-            }
-            SlackerPacketBody::Interrupt(ref req) => {
-                try!(buf.write_i32::<BigEndian>(req.req_id));
-            }
+        let (_, packet) = frame_in;
+        if packet.0.packet_type == PACKET_TYPE_NO_RESPONSE {
+            return Ok(());
+        }
+        let start = buf.len();
+        // leaves no partial packet behind
+        if let Err(e) = write_packet(&mut buf.writer(), &packet) {
+            buf.truncate(start);
+            return Err(e);
+        }
+        if let Some(ref metrics) = self.metrics {
+            metrics.bytes_written(buf.len() - start);
         }
         Ok(())
     }
@@ -73,18 +127,111 @@ impl Decoder for SlackerCodec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         let (consumed, result) = match slacker_all(buf.as_ref()) {
-            IResult::Done(i, out) => {
+            Ok((i, out)) => {
                 let SlackerPacket(header, _) = out;
                 debug!("data in {:?}", header);
                 let request_id = header.serial_id;
 
                 (buf.offset(i), Some((request_id as RequestId, out)))
             }
-            IResult::Incomplete(_) => return Ok(None),
-            IResult::Error(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+            Err(::nom::Err::Incomplete(_)) => return Ok(None),
+            Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, format!("{:?}", e))),
         };
 
         buf.split_to(consumed);
+        if let Some(ref metrics) = self.metrics {
+            metrics.bytes_read(consumed);
+        }
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: u8, extensions: Vec<SlackerExtension>) -> (RequestId, SlackerPacket) {
+        let header = SlackerPacketHeader {
+            version,
+            serial_id: 7,
+            packet_type: PACKET_TYPE_REQUEST,
+        };
+        let body = SlackerPacketBody::Request(SlackerRequestPacket {
+            content_type: JSON_CONTENT_TYPE,
+            fname: "demo/f".to_owned(),
+            arguments: b"[1]".to_vec(),
+            extensions,
+        });
+        (7, SlackerPacket(header, body))
+    }
+
+    fn extension(ext_id: i16) -> SlackerExtension {
+        SlackerExtension {
+            ext_id,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    fn roundtrip(packet: (RequestId, SlackerPacket)) -> SlackerPacket {
+        let mut codec = SlackerCodec::new(None);
+        let mut buf = BytesMut::with_capacity(1024);
+        codec.encode(packet, &mut buf).unwrap();
+        let (_, packet) = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        packet
+    }
+
+    #[test]
+    fn v6_packets_carry_extensions() {
+        let sent = vec![extension(EXTENSION_ID_AUTH_TOKEN), extension(7)];
+        match roundtrip(request(PROTOCOL_VERSION_6, sent)) {
+            SlackerPacket(header, SlackerPacketBody::Request(req)) => {
+                assert_eq!(header.serial_id, 7);
+                assert_eq!(req.fname, "demo/f");
+                assert_eq!(req.arguments, b"[1]".to_vec());
+                let ids: Vec<i16> = req.extensions.iter().map(|ext| ext.ext_id).collect();
+                assert_eq!(ids, vec![EXTENSION_ID_AUTH_TOKEN, 7]);
+                assert_eq!(
+                    find_extension(&req.extensions, EXTENSION_ID_AUTH_TOKEN),
+                    Some(&[1, 2, 3][..])
+                );
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn v5_packets_leave_extensions_out() {
+        match roundtrip(request(
+            PROTOCOL_VERSION_5,
+            vec![extension(EXTENSION_ID_AUTH_TOKEN)],
+        )) {
+            SlackerPacket(_, SlackerPacketBody::Request(req)) => assert!(req.extensions.is_empty()),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn too_many_extensions_are_rejected() {
+        let mut codec = SlackerCodec::new(None);
+        let mut buf = BytesMut::with_capacity(8192);
+        let extensions = (0..256).map(extension).collect();
+        let e = codec
+            .encode(request(PROTOCOL_VERSION_6, extensions), &mut buf)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn unanswered_packets_are_not_written() {
+        let mut codec = SlackerCodec::new(None);
+        let mut buf = BytesMut::with_capacity(1024);
+        let (id, SlackerPacket(mut header, body)) = request(PROTOCOL_VERSION_6, Vec::new());
+        header.packet_type = PACKET_TYPE_NO_RESPONSE;
+        codec
+            .encode((id, SlackerPacket(header, body)), &mut buf)
+            .unwrap();
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::any::Any;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use addr::SlackerAddr;
+use interceptor::ConnectionInfo;
+use notify::Notifier;
+use parser::*;
+use trace::TraceContext;
+
+/// State shared with handlers: the application state of a server, or the
+/// state of a connection created by its session factory.
+#[derive(Clone)]
+pub struct AnyState(Arc<dyn Any + Send + Sync>);
+
+impl AnyState {
+    pub fn new<S: Any + Send + Sync>(state: S) -> AnyState {
+        AnyState(Arc::new(state))
+    }
+
+    pub fn downcast_ref<S: Any>(&self) -> Option<&S> {
+        self.0.downcast_ref()
+    }
+
+    pub fn downcast<S: Any + Send + Sync>(&self) -> Option<Arc<S>> {
+        self.0.clone().downcast().ok()
+    }
+}
+
+impl fmt::Debug for AnyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AnyState")
+    }
+}
+
+/// What a handler can know about the request it is serving.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub peer_addr: Option<SlackerAddr>,
+    pub serial_id: i32,
+    pub content_type: u8,
+    pub extensions: Vec<SlackerExtension>,
+    pub principal: Option<String>,
+    pub trace: Option<TraceContext>,
+    session: Option<AnyState>,
+    state: Option<AnyState>,
+    notifier: Notifier,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RequestContext {
+    pub fn new(
+        conn: &ConnectionInfo,
+        header: &SlackerPacketHeader,
+        req: &SlackerRequestPacket,
+    ) -> RequestContext {
+        RequestContext {
+            peer_addr: conn.peer_addr.clone(),
+            serial_id: header.serial_id,
+            content_type: req.content_type,
+            extensions: req.extensions.clone(),
+            principal: conn.principal(),
+            trace: TraceContext::current(),
+            session: conn.session_state().cloned(),
+            state: conn.state().cloned(),
+            notifier: conn.notifier().clone(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The state of this connection, if the server has a session factory
+    /// creating an `S`.
+    pub fn session<S: Any>(&self) -> Option<&S> {
+        self.session.as_ref().and_then(|s| s.downcast_ref())
+    }
+
+    /// The application state of the server, if it is an `S`.
+    pub fn state<S: Any + Send + Sync>(&self) -> Option<State<S>> {
+        self.state.as_ref().and_then(|s| s.downcast()).map(State)
+    }
+
+    /// Sends notifications to the calling connection.
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn extension(&self, ext_id: i16) -> Option<&[u8]> {
+        find_extension(&self.extensions, ext_id)
+    }
+
+    /// Whether the response is no longer awaited, e.g. because the
+    /// connection was closed. Long running handlers may give up early.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the request once dropped.
+    pub fn cancel_guard(&self) -> CancelGuard {
+        CancelGuard(self.cancelled.clone())
+    }
+}
+
+pub struct CancelGuard(Arc<AtomicBool>);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The application state given to `ServerBuilder::state`, extracted for
+/// handlers built with `RpcHandler::typed`.
+pub struct State<S>(pub Arc<S>);
+
+impl<S> Clone for State<S> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<S> Deref for State<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+/// What a typed handler takes from a request besides its arguments.
+pub trait FromContext: Sized {
+    fn from_context(ctx: &RequestContext) -> Option<Self>;
+}
+
+impl<S: Any + Send + Sync> FromContext for State<S> {
+    fn from_context(ctx: &RequestContext) -> Option<Self> {
+        ctx.state()
+    }
+}
+
+impl FromContext for RequestContext {
+    fn from_context(ctx: &RequestContext) -> Option<Self> {
+        Some(ctx.clone())
+    }
+}
+
+impl<A: FromContext, B: FromContext> FromContext for (A, B) {
+    fn from_context(ctx: &RequestContext) -> Option<Self> {
+        Some((A::from_context(ctx)?, B::from_context(ctx)?))
+    }
+}
@@ -0,0 +1,319 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::future::{err, ok, Either};
+use futures::sync::{mpsc, oneshot};
+use futures::{unsync, Future, Poll, Sink, Stream};
+use tcore::reactor::Handle;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_codec::Decoder;
+use tproto::multiplex::RequestId;
+use tservice::Service;
+
+use codecs::SlackerCodec;
+use metrics::Metrics;
+use parser::*;
+use service::{error_response, BoxService};
+
+struct Shared {
+    // outgoing calls waiting for their response, by serial id
+    pending: HashMap<i32, oneshot::Sender<SlackerPacket>>,
+    // calls receiving packets pushed before their response, by serial id
+    subscribers: HashMap<i32, unsync::mpsc::UnboundedSender<SlackerPacket>>,
+    closed: bool,
+}
+
+// Whether the server pushed `packet` under the serial id of a call rather
+// than answering it, like the chunks of a stream or notifications.
+fn is_pushed(packet: &SlackerPacket) -> bool {
+    match packet.1 {
+        SlackerPacketBody::Response(ref r) => {
+            stream_op(&r.extensions).map(|(op, _)| op) == Some(STREAM_CHUNK)
+                || find_extension(&r.extensions, EXTENSION_ID_NOTIFICATIONS).is_some()
+        }
+        _ => false,
+    }
+}
+
+/// The connection of a client, which also answers the requests its server
+/// sends with `service`, if any. Packets are told apart by type: responses
+/// go to the calls waiting for them and requests to `service`.
+/// The connection is closed once all clones are dropped.
+#[derive(Clone)]
+pub struct ClientConnection {
+    tx: mpsc::UnboundedSender<SlackerPacket>,
+    shared: Rc<RefCell<Shared>>,
+    // dropped along with the last clone
+    _hang_up: Rc<unsync::oneshot::Sender<()>>,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed.")
+}
+
+// Fails the calls still waiting.
+fn close(shared: &RefCell<Shared>) {
+    let mut shared = shared.borrow_mut();
+    shared.closed = true;
+    shared.pending.clear();
+    shared.subscribers.clear();
+}
+
+impl ClientConnection {
+    pub fn bind<T>(
+        handle: &Handle,
+        io: T,
+        service: Option<BoxService>,
+        metrics: Option<Arc<dyn Metrics>>,
+    ) -> ClientConnection
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let (sink, stream) = SlackerCodec::new(metrics).framed(io).split();
+        let (tx, rx) = mpsc::unbounded::<SlackerPacket>();
+        let shared = Rc::new(RefCell::new(Shared {
+            pending: HashMap::new(),
+            subscribers: HashMap::new(),
+            closed: false,
+        }));
+
+        // the reader answers requests through `tx` as well, so the
+        // connection is closed when the clients hang up rather than when
+        // `rx` ends
+        let (hang_up, hung_up) = unsync::oneshot::channel::<()>();
+        let hung_up = hung_up.shared();
+
+        let writer_shared = shared.clone();
+        handle.spawn(
+            sink.send_all(
+                rx.map(|packet| (packet.0.serial_id as RequestId, packet))
+                    .map_err(|_| closed()),
+            )
+            .map(|_| ())
+            .select2(hung_up.clone())
+            .then(move |r| {
+                if let Err(Either::A((e, _))) = r {
+                    debug!("failed to write to connection: {}", e);
+                }
+                close(&writer_shared);
+                Ok(())
+            }),
+        );
+
+        let responder = tx.clone();
+        let reader_shared = shared.clone();
+        let spawner = handle.clone();
+        let closing = shared.clone();
+        handle.spawn(
+            stream
+                .for_each(move |(_, packet)| {
+                    match packet.0.packet_type {
+                        PACKET_TYPE_REQUEST | PACKET_TYPE_PING | PACKET_TYPE_INSPECT_REQUEST => {
+                            let responder = responder.clone();
+                            let resp = match service {
+                                Some(ref service) => service.call(packet),
+                                None => {
+                                    Box::new(ok(error_response(packet.0, RESULT_CODE_NOT_FOUND)))
+                                }
+                            };
+                            spawner.spawn(resp.then(move |r| {
+                                match r {
+                                    Ok(resp) => {
+                                        let _ = responder.unbounded_send(resp);
+                                    }
+                                    Err(e) => warn!("failed to serve request: {}", e),
+                                }
+                                Ok(())
+                            }));
+                        }
+                        _ if is_pushed(&packet) => {
+                            let serial_id = packet.0.serial_id;
+                            let mut shared = reader_shared.borrow_mut();
+                            let received = match shared.subscribers.get(&serial_id) {
+                                Some(subscriber) => subscriber.unbounded_send(packet).is_ok(),
+                                None => false,
+                            };
+                            if !received {
+                                debug!("no call waiting for packets of {}", serial_id);
+                                shared.subscribers.remove(&serial_id);
+                            }
+                        }
+                        _ => {
+                            let serial_id = packet.0.serial_id;
+                            let mut shared = reader_shared.borrow_mut();
+                            // the response ends what was pushed, and goes to
+                            // the subscriber if nothing else waits for it, e.g.
+                            // when a subscription is rejected
+                            let subscriber = shared.subscribers.remove(&serial_id);
+                            match (shared.pending.remove(&serial_id), subscriber) {
+                                (Some(waiter), _) => {
+                                    let _ = waiter.send(packet);
+                                }
+                                (None, Some(subscriber)) => {
+                                    let _ = subscriber.unbounded_send(packet);
+                                }
+                                _ => debug!("no call waiting for response {}", serial_id),
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+                .select2(hung_up)
+                .then(move |r| {
+                    if let Err(Either::A((e, _))) = r {
+                        debug!("failed to read from connection: {}", e);
+                    }
+                    close(&closing);
+                    Ok(())
+                }),
+        );
+
+        ClientConnection {
+            tx,
+            shared,
+            _hang_up: Rc::new(hang_up),
+        }
+    }
+
+    /// Receives the packets pushed ahead of the response to call
+    /// `serial_id`, which ends once the connection is closed.
+    pub fn subscribe(&self, serial_id: i32) -> unsync::mpsc::UnboundedReceiver<SlackerPacket> {
+        let (tx, rx) = unsync::mpsc::unbounded();
+        let mut shared = self.shared.borrow_mut();
+        if !shared.closed {
+            shared.subscribers.insert(serial_id, tx);
+        }
+        rx
+    }
+
+    /// Writes a request without waiting for its response.
+    pub fn send(&self, req: SlackerPacket) -> io::Result<()> {
+        if self.shared.borrow().closed {
+            return Err(closed());
+        }
+        self.tx.unbounded_send(req).map_err(|_| closed())
+    }
+}
+
+impl Service for ClientConnection {
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (waiter, rx) = oneshot::channel();
+        {
+            let mut shared = self.shared.borrow_mut();
+            if shared.closed {
+                return Box::new(err(closed()));
+            }
+            shared.pending.insert(req.0.serial_id, waiter);
+        }
+        let serial_id = req.0.serial_id;
+        if self.tx.unbounded_send(req).is_err() {
+            self.shared.borrow_mut().pending.remove(&serial_id);
+            return Box::new(err(closed()));
+        }
+        Box::new(Response {
+            rx,
+            serial_id,
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+// Stops waiting for the response once dropped, e.g. when the call timed out.
+struct Response {
+    rx: oneshot::Receiver<SlackerPacket>,
+    serial_id: i32,
+    shared: Rc<RefCell<Shared>>,
+}
+
+impl Future for Response {
+    type Item = SlackerPacket;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<SlackerPacket, io::Error> {
+        self.rx.poll().map_err(|_| closed())
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        self.shared.borrow_mut().pending.remove(&self.serial_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+    use tcore::net::TcpStream as AsyncTcpStream;
+    use tcore::reactor::Core;
+
+    fn connect(core: &mut Core) -> (ClientConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let io = core
+            .run(AsyncTcpStream::connect(&addr, &core.handle()))
+            .unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        let conn = ClientConnection::bind(&core.handle(), io, None, None);
+        (conn, peer)
+    }
+
+    fn ping(serial_id: i32) -> SlackerPacket {
+        let header = SlackerPacketHeader {
+            version: PROTOCOL_VERSION_6,
+            serial_id,
+            packet_type: PACKET_TYPE_PING,
+        };
+        SlackerPacket(header, SlackerPacketBody::Ping)
+    }
+
+    #[test]
+    fn dropped_calls_stop_waiting() {
+        let mut core = Core::new().unwrap();
+        let (conn, _peer) = connect(&mut core);
+        let call = conn.call(ping(1));
+        assert_eq!(conn.shared.borrow().pending.len(), 1);
+        drop(call);
+        assert!(conn.shared.borrow().pending.is_empty());
+    }
+
+    #[test]
+    fn calls_fail_once_the_peer_closes() {
+        let mut core = Core::new().unwrap();
+        let (conn, peer) = connect(&mut core);
+        let waiting = conn.call(ping(1));
+        drop(peer);
+        let e = core.run(waiting).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            conn.send(ping(2)).err().unwrap().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            core.run(conn.call(ping(3))).err().unwrap().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn dropping_the_clients_closes_the_connection() {
+        let mut core = Core::new().unwrap();
+        let (conn, mut peer) = connect(&mut core);
+        drop(conn);
+        for _ in 0..10 {
+            core.turn(Some(Duration::from_millis(10)));
+        }
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(peer.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
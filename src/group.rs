@@ -0,0 +1,145 @@
+use std::io;
+
+use futures::{Async, Future, Poll};
+
+use serde_json::value::Value as Json;
+
+use addr::SlackerAddr;
+use client::Client;
+
+pub type GroupResults = Vec<(SlackerAddr, io::Result<Json>)>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GroupMode {
+    /// Wait for every server, successful or not.
+    All,
+    /// Resolve as soon as one server succeeds.
+    FirstSuccess,
+    /// Resolve as soon as the given number of servers succeed.
+    Quorum(usize),
+}
+
+/// Runs one call per server in parallel. Resolves with the per-server results
+/// collected so far once the mode is satisfied, or fails when it no longer
+/// can be.
+pub struct GroupCall {
+    mode: GroupMode,
+    pending: Vec<(SlackerAddr, Box<dyn Future<Item = Json, Error = io::Error>>)>,
+    results: GroupResults,
+    successes: usize,
+    dispatched: usize,
+}
+
+impl GroupCall {
+    pub fn new(
+        calls: Vec<(SlackerAddr, Box<dyn Future<Item = Json, Error = io::Error>>)>,
+        mode: GroupMode,
+    ) -> GroupCall {
+        GroupCall {
+            mode,
+            results: Vec::with_capacity(calls.len()),
+            dispatched: calls.len(),
+            pending: calls,
+            successes: 0,
+        }
+    }
+
+    fn required(&self) -> usize {
+        match self.mode {
+            GroupMode::All => self.dispatched,
+            GroupMode::FirstSuccess => 1,
+            GroupMode::Quorum(n) => n,
+        }
+    }
+}
+
+impl Future for GroupCall {
+    type Item = GroupResults;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let r = match self.pending[i].1.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(v)) => Ok(v),
+                Err(e) => Err(e),
+            };
+
+            let (addr, _) = self.pending.swap_remove(i);
+            if r.is_ok() {
+                self.successes += 1;
+            }
+            self.results.push((addr, r));
+        }
+
+        if self.mode == GroupMode::All {
+            if self.pending.is_empty() {
+                return Ok(Async::Ready(self.results.drain(..).collect()));
+            }
+        } else if self.successes >= self.required() {
+            return Ok(Async::Ready(self.results.drain(..).collect()));
+        } else if self.successes + self.pending.len() < self.required() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} of {} servers succeeded, {} required",
+                    self.successes,
+                    self.dispatched,
+                    self.required()
+                ),
+            ));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+pub fn group_call<'a, I>(
+    clients: I,
+    ns_name: &str,
+    fn_name: &str,
+    args: Vec<Json>,
+    mode: GroupMode,
+) -> GroupCall
+where
+    I: IntoIterator<Item = &'a Client>,
+{
+    let calls = clients
+        .into_iter()
+        .map(|c| (c.addr().clone(), c.rpc_call(ns_name, fn_name, args.clone())))
+        .collect();
+    GroupCall::new(calls, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{empty, err, ok};
+
+    fn addr(port: u16) -> SlackerAddr {
+        SlackerAddr::Tcp(([127, 0, 0, 1], port).into())
+    }
+
+    #[test]
+    fn quorum_failure_reports_dispatched_calls() {
+        let calls: Vec<(SlackerAddr, Box<dyn Future<Item = Json, Error = io::Error>>)> = vec![
+            (addr(1), Box::new(ok(Json::Null))),
+            (
+                addr(2),
+                Box::new(err(io::Error::new(io::ErrorKind::Other, "down"))),
+            ),
+            (
+                addr(3),
+                Box::new(err(io::Error::new(io::ErrorKind::Other, "down"))),
+            ),
+            (addr(4), Box::new(empty())),
+        ];
+        let e = GroupCall::new(calls, GroupMode::Quorum(3))
+            .wait()
+            .unwrap_err();
+        assert_eq!(e.to_string(), "1 of 4 servers succeeded, 3 required");
+    }
+}
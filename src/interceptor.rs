@@ -0,0 +1,164 @@
+use std::any::Any;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use futures::future::ok;
+use futures::sync::mpsc::Sender;
+use futures::Future;
+use tservice::Service;
+
+use addr::SlackerAddr;
+use context::AnyState;
+use notify::Notifier;
+use parser::*;
+use service::error_response;
+
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    /// None for Unix domain socket peers that didn't bind a path.
+    pub peer_addr: Option<SlackerAddr>,
+    /// DER encoded certificate chain presented by a TLS client, if any.
+    pub peer_certificates: Vec<Vec<u8>>,
+    principal: RwLock<Option<String>>,
+    session: Option<AnyState>,
+    state: Option<AnyState>,
+    notifier: Notifier,
+    push: Option<Sender<SlackerPacket>>,
+}
+
+impl ConnectionInfo {
+    pub fn new(peer_addr: Option<SlackerAddr>) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr,
+            peer_certificates: Vec::new(),
+            principal: RwLock::new(None),
+            session: None,
+            state: None,
+            notifier: Notifier::new(),
+            push: None,
+        }
+    }
+
+    /// The peer address for logs.
+    pub fn peer_name(&self) -> String {
+        match self.peer_addr {
+            Some(ref addr) => addr.to_string(),
+            None => "unnamed peer".to_owned(),
+        }
+    }
+
+    /// The principal this connection authenticated as, if any.
+    pub fn principal(&self) -> Option<String> {
+        self.principal.read().unwrap().clone()
+    }
+
+    pub fn set_principal(&self, principal: String) {
+        *self.principal.write().unwrap() = Some(principal);
+    }
+
+    /// The state of this connection, if the server has a session factory
+    /// creating an `S`.
+    pub fn session<S: Any>(&self) -> Option<&S> {
+        self.session.as_ref().and_then(|s| s.downcast_ref())
+    }
+
+    pub fn session_state(&self) -> Option<&AnyState> {
+        self.session.as_ref()
+    }
+
+    pub fn set_session(&mut self, session: AnyState) {
+        self.session = Some(session);
+    }
+
+    /// The application state of the server, if it has one.
+    pub fn state(&self) -> Option<&AnyState> {
+        self.state.as_ref()
+    }
+
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn set_state(&mut self, state: AnyState) {
+        self.state = Some(state);
+    }
+
+    /// Writes packets besides the responses, like the chunks of a stream.
+    /// Servers have one, clients don't.
+    pub fn push(&self) -> Option<&Sender<SlackerPacket>> {
+        self.push.as_ref()
+    }
+
+    pub fn set_push(&mut self, push: Sender<SlackerPacket>) {
+        self.notifier.attach(push.clone());
+        self.push = Some(push);
+    }
+}
+
+/// Runs when a connection is accepted and when it is closed.
+pub trait ConnectionHook: Send + Sync + 'static {
+    fn on_connect(&self, _conn: &ConnectionInfo) {}
+
+    fn on_disconnect(&self, _conn: &ConnectionInfo) {}
+}
+
+/// Runs before a request is dispatched to its function. Returning an error
+/// rejects the request with the given result code.
+pub trait Interceptor: Send + Sync + 'static {
+    fn intercept(&self, conn: &ConnectionInfo, fname: &str) -> Result<(), u8>;
+}
+
+pub struct Intercepted<S> {
+    inner: S,
+    conn: Arc<ConnectionInfo>,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+}
+
+impl<S> Intercepted<S> {
+    pub fn new(
+        inner: S,
+        conn: Arc<ConnectionInfo>,
+        interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    ) -> Intercepted<S> {
+        Intercepted {
+            inner,
+            conn,
+            interceptors,
+        }
+    }
+}
+
+impl<S> Service for Intercepted<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let rejected = match req {
+            SlackerPacket(_, SlackerPacketBody::Request(ref sreq)) => self
+                .interceptors
+                .iter()
+                .filter_map(|i| i.intercept(&self.conn, &sreq.fname).err())
+                .next(),
+            _ => None,
+        };
+
+        match rejected {
+            Some(result_code) => {
+                let SlackerPacket(header, _) = req;
+                debug!(
+                    "request from {} rejected with {}",
+                    self.conn.peer_name(),
+                    result_code
+                );
+                Box::new(ok(error_response(header, result_code)))
+            }
+            None => Box::new(self.inner.call(req)),
+        }
+    }
+}
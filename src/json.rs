@@ -4,5 +4,7 @@ use service::*;
 
 pub type JsonRpcFn = RpcFn<Json>;
 pub type JsonRpcFnSync = RpcFnSync<Json>;
-
-pub struct JsonSlacker;
+pub type JsonRpcFnWithContext = RpcFnWithContext<Json>;
+pub type JsonRpcFnSyncWithContext = RpcFnSyncWithContext<Json>;
+pub type JsonRpcFnStream = RpcFnStream<Json>;
+pub type JsonRpcHandler = RpcHandler<Json>;
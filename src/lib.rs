@@ -1,4 +1,3 @@
-#![allow(dead_code, unused_must_use)]
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -8,79 +7,71 @@ extern crate byteorder;
 extern crate bytes;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate rand;
+#[cfg(feature = "tls")]
+extern crate rustls;
 extern crate serde;
 extern crate serde_json;
 extern crate tokio;
 extern crate tokio_codec;
+extern crate tokio_core as tcore;
+extern crate tokio_io;
+extern crate tokio_proto as tproto;
+#[cfg(feature = "tls")]
+extern crate tokio_rustls;
+extern crate tokio_service as tservice;
+#[cfg(unix)]
+extern crate tokio_uds as tuds;
+extern crate tracing;
 
+mod addr;
+mod auth;
+mod breaker;
 mod client;
+mod cluster;
 mod codecs;
+mod context;
+mod duplex;
+mod group;
+mod interceptor;
 mod json;
+mod limit;
+mod metrics;
+mod notify;
 mod parser;
+mod queue;
+mod registry;
+mod retry;
 mod serializer;
+mod server;
 mod service;
+#[cfg(feature = "tls")]
+mod tls;
+mod trace;
 
-use tokio::net::{TcpStream, TcpListener};
-
-use std::collections::BTreeMap;
-use std::io;
-use std::net::SocketAddr;
-use std::sync::Arc;
-
-pub use client::{Client, ClientManager};
-use codecs::*;
-use json::*;
-pub use json::{JsonRpcFn, JsonRpcFnSync};
-use parser::*;
-use serializer::*;
-use service::*;
-
-pub struct Server {
-    addr: SocketAddr,
-    funcs: Arc<BTreeMap<String, JsonRpcFn>>,
-}
-
-impl Server {
-    pub fn new(addr: SocketAddr, funcs: BTreeMap<String, JsonRpcFn>) -> Self {
-        Server {
-            addr,
-            funcs: Arc::new(funcs),
-        }
-    }
-
-    pub fn serve(&self) {
-        let serializer = Arc::new(JsonSerializer);
-        let funcs_ref = self.funcs.clone();
-        TcpServer::new(JsonSlacker, self.addr)
-            .serve(move || Ok(SlackerService::new(funcs_ref.clone(), serializer.clone())));
-    }
-}
-
-pub struct ThreadPoolServer {
-    addr: SocketAddr,
-    funcs: Arc<BTreeMap<String, JsonRpcFnSync>>,
-    threads: usize,
-}
-
-impl ThreadPoolServer {
-    pub fn new(addr: SocketAddr, funcs: BTreeMap<String, JsonRpcFnSync>, threads: usize) -> Self {
-        ThreadPoolServer {
-            addr,
-            funcs: Arc::new(funcs),
-            threads,
-        }
-    }
-
-    pub fn serve(&self) {
-        let serializer = Arc::new(JsonSerializer);
-        let funcs_ref = self.funcs.clone();
-        let threads = self.threads;
-        TcpServer::new(JsonSlacker, self.addr).serve(move || {
-            Ok(SlackerServiceSync::new(
-                funcs_ref.clone(),
-                serializer.clone(),
-                threads,
-            ))
-        });
-    }
-}
+pub use addr::SlackerAddr;
+pub use auth::{Acl, Authenticator, Authorizer};
+pub use breaker::{CircuitBreaker, CircuitOpen, CircuitPermit};
+pub use client::{Batch, Client, ClientManager};
+pub use cluster::{
+    ClusterClient, ClusterRegistry, Discovery, FileDiscovery, LoadBalance, MemoryRegistry,
+    StaticDiscovery, ZkClient, ZkDiscovery, ZkRegistry,
+};
+pub use context::{FromContext, RequestContext, State};
+pub use group::{group_call, GroupCall, GroupMode, GroupResults};
+pub use interceptor::{ConnectionHook, ConnectionInfo, Interceptor};
+pub use json::{
+    JsonRpcFn, JsonRpcFnStream, JsonRpcFnSync, JsonRpcFnSyncWithContext, JsonRpcFnWithContext,
+    JsonRpcHandler,
+};
+pub use limit::Limit;
+pub use metrics::{metrics_endpoint, Metrics, PrometheusMetrics};
+pub use notify::Notifier;
+pub use queue::{JobQueue, QueuePolicy};
+pub use registry::FunctionRegistry;
+pub use retry::RetryPolicy;
+pub use server::{Server, ServerBuilder, ThreadPoolServer};
+pub use service::{RpcHandler, RpcStream};
+#[cfg(feature = "tls")]
+pub use tls::{TlsClientConfig, TlsServerConfig};
+pub use trace::TraceContext;
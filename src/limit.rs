@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::rc::Rc;
+use std::time::Instant;
+
+use futures::future::ok;
+use futures::Future;
+use tservice::Service;
+
+use parser::*;
+use service::error_response;
+
+/// Caps the request rate and the number of requests in flight. Requests over
+/// either cap are rejected with `RESULT_CODE_SERVER_BUSY`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Limit {
+    rate: Option<(f64, f64)>,
+    max_in_flight: Option<usize>,
+}
+
+impl Limit {
+    pub fn new() -> Limit {
+        Limit::default()
+    }
+
+    /// Token bucket refilled with `per_second` tokens, holding at most `burst`.
+    pub fn rate(mut self, per_second: f64, burst: u32) -> Limit {
+        self.rate = Some((per_second, f64::from(burst)));
+        self
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Limit {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+}
+
+pub struct LimitState {
+    limit: Limit,
+    tokens: f64,
+    refilled_at: Instant,
+    in_flight: usize,
+}
+
+impl LimitState {
+    pub fn new(limit: Limit) -> Rc<RefCell<LimitState>> {
+        Rc::new(RefCell::new(LimitState {
+            limit,
+            tokens: limit.rate.map_or(0.0, |(_, burst)| burst),
+            refilled_at: Instant::now(),
+            in_flight: 0,
+        }))
+    }
+
+    // Refills the bucket and tells whether a request may start.
+    fn has_room(&mut self) -> bool {
+        if matches!(self.limit.max_in_flight, Some(max) if self.in_flight >= max) {
+            return false;
+        }
+
+        if let Some((per_second, burst)) = self.limit.rate {
+            let now = Instant::now();
+            let elapsed = now - self.refilled_at;
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            self.tokens = (self.tokens + elapsed * per_second).min(burst);
+            self.refilled_at = now;
+            if self.tokens < 1.0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn take(&mut self) {
+        if self.limit.rate.is_some() {
+            self.tokens -= 1.0;
+        }
+        self.in_flight += 1;
+    }
+}
+
+// Holds an in-flight slot until the response is ready.
+struct Permit(Rc<RefCell<LimitState>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.borrow_mut().in_flight -= 1;
+    }
+}
+
+/// Applies the limit of its own connection and the limits of each function,
+/// the latter shared by all connections of a server.
+pub struct Limited<S> {
+    inner: S,
+    connection: Option<Rc<RefCell<LimitState>>>,
+    functions: Rc<BTreeMap<String, Rc<RefCell<LimitState>>>>,
+}
+
+impl<S> Limited<S> {
+    pub fn new(
+        inner: S,
+        connection: Option<Limit>,
+        functions: Rc<BTreeMap<String, Rc<RefCell<LimitState>>>>,
+    ) -> Limited<S> {
+        Limited {
+            inner,
+            connection: connection.map(LimitState::new),
+            functions,
+        }
+    }
+
+    // Takes from every limit that applies only if all of them have room, so
+    // a rejected request uses up no tokens.
+    fn acquire(&self, fname: &str) -> Option<Vec<Permit>> {
+        let states: Vec<&Rc<RefCell<LimitState>>> = self
+            .connection
+            .iter()
+            .chain(self.functions.get(fname))
+            .collect();
+        if !states.iter().all(|state| state.borrow_mut().has_room()) {
+            return None;
+        }
+        Some(
+            states
+                .into_iter()
+                .map(|state| {
+                    state.borrow_mut().take();
+                    Permit(state.clone())
+                })
+                .collect(),
+        )
+    }
+}
+
+impl<S> Service for Limited<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let permits = match req {
+            SlackerPacket(_, SlackerPacketBody::Request(ref sreq)) => self.acquire(&sreq.fname),
+            _ => Some(Vec::new()),
+        };
+
+        match permits {
+            Some(permits) => Box::new(self.inner.call(req).then(move |r| {
+                drop(permits);
+                r
+            })),
+            None => {
+                let SlackerPacket(header, _) = req;
+                debug!("server busy, rejecting request {}", header.serial_id);
+                Box::new(ok(error_response(header, RESULT_CODE_SERVER_BUSY)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::empty;
+    use futures::Async;
+
+    // Never answers, holding its permits until dropped.
+    struct Pending;
+
+    impl Service for Pending {
+        type Request = SlackerPacket;
+        type Response = SlackerPacket;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _: Self::Request) -> Self::Future {
+            Box::new(empty())
+        }
+    }
+
+    fn request(fname: &str) -> SlackerPacket {
+        let header = SlackerPacketHeader {
+            version: PROTOCOL_VERSION_6,
+            serial_id: 1,
+            packet_type: PACKET_TYPE_REQUEST,
+        };
+        let body = SlackerPacketBody::Request(SlackerRequestPacket {
+            content_type: JSON_CONTENT_TYPE,
+            fname: fname.to_owned(),
+            arguments: b"[]".to_vec(),
+            extensions: Vec::new(),
+        });
+        SlackerPacket(header, body)
+    }
+
+    fn is_busy<F: Future<Item = SlackerPacket>>(mut f: F) -> bool {
+        match f.poll() {
+            Ok(Async::Ready(SlackerPacket(_, SlackerPacketBody::Error(e)))) => {
+                e.result_code == RESULT_CODE_SERVER_BUSY
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn in_flight_requests_hold_their_slot() {
+        let limited = Limited::new(Pending, Some(Limit::new().max_in_flight(1)), Rc::default());
+        let first = limited.call(request("demo/f"));
+        assert!(is_busy(limited.call(request("demo/f"))));
+        drop(first);
+        assert!(!is_busy(limited.call(request("demo/f"))));
+    }
+
+    #[test]
+    fn rate_allows_bursts() {
+        let limited = Limited::new(Pending, Some(Limit::new().rate(0.001, 2)), Rc::default());
+        assert!(!is_busy(limited.call(request("demo/f"))));
+        assert!(!is_busy(limited.call(request("demo/f"))));
+        assert!(is_busy(limited.call(request("demo/f"))));
+    }
+
+    #[test]
+    fn function_limits_apply_to_their_function() {
+        let mut functions = BTreeMap::new();
+        functions.insert(
+            "demo/f".to_owned(),
+            LimitState::new(Limit::new().max_in_flight(1)),
+        );
+        let limited = Limited::new(Pending, None, Rc::new(functions));
+        let _first = limited.call(request("demo/f"));
+        assert!(is_busy(limited.call(request("demo/f"))));
+        assert!(!is_busy(limited.call(request("demo/g"))));
+    }
+
+    #[test]
+    fn rejected_requests_use_up_no_tokens() {
+        let mut functions = BTreeMap::new();
+        functions.insert(
+            "demo/f".to_owned(),
+            LimitState::new(Limit::new().max_in_flight(1)),
+        );
+        let limited = Limited::new(
+            Pending,
+            Some(Limit::new().rate(0.001, 2)),
+            Rc::new(functions),
+        );
+        let _first = limited.call(request("demo/f"));
+        assert!(is_busy(limited.call(request("demo/f"))));
+        assert!(!is_busy(limited.call(request("demo/g"))));
+        assert!(is_busy(limited.call(request("demo/g"))));
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use tcore::net::TcpListener;
+use tcore::reactor::Handle;
+use tokio::io::{read, write_all};
+use tservice::Service;
+
+use parser::*;
+
+/// Receives the metrics of a server or a client.
+pub trait Metrics: Send + Sync + 'static {
+    /// `result_code` is `None` when the call failed without a response, e.g.
+    /// because its connection was lost.
+    fn call(&self, _fname: &str, _result_code: Option<u8>, _latency: Duration) {}
+
+    fn in_flight(&self, _delta: isize) {}
+
+    fn connections(&self, _delta: isize) {}
+
+    fn bytes_read(&self, _bytes: usize) {}
+
+    fn bytes_written(&self, _bytes: usize) {}
+
+    /// Jobs waiting for a thread of the named pool of a `ThreadPoolServer`.
+    fn queue_depth(&self, _pool: &str, _depth: usize) {}
+
+    /// Prometheus text exposition of what has been collected, served by
+    /// `metrics_endpoint`.
+    fn render(&self) -> String {
+        String::new()
+    }
+}
+
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // cumulative, one per bucket
+    buckets: [u64; 12],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Collected {
+    calls: BTreeMap<(String, String), u64>,
+    latencies: BTreeMap<(String, String), Histogram>,
+    queue_depths: BTreeMap<String, usize>,
+}
+
+/// Counts calls and records latency histograms per function and result code,
+/// and tracks in-flight calls, open connections, bytes transferred and queue
+/// depths.
+pub struct PrometheusMetrics {
+    prefix: String,
+    collected: Mutex<Collected>,
+    in_flight: AtomicIsize,
+    connections: AtomicIsize,
+    bytes_read: AtomicUsize,
+    bytes_written: AtomicUsize,
+}
+
+impl PrometheusMetrics {
+    /// `prefix` starts every metric name, e.g. `slacker_server`.
+    pub fn new(prefix: &str) -> PrometheusMetrics {
+        PrometheusMetrics {
+            prefix: prefix.to_owned(),
+            collected: Mutex::new(Collected::default()),
+            in_flight: AtomicIsize::new(0),
+            connections: AtomicIsize::new(0),
+            bytes_read: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics for PrometheusMetrics {
+    fn call(&self, fname: &str, result_code: Option<u8>, latency: Duration) {
+        let result = result_code.map_or_else(|| "error".to_owned(), |c| c.to_string());
+        let seconds = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) / 1e9;
+
+        let mut collected = self.collected.lock().unwrap();
+        *collected
+            .calls
+            .entry((fname.to_owned(), result.clone()))
+            .or_insert(0) += 1;
+        let histogram = collected
+            .latencies
+            .entry((fname.to_owned(), result))
+            .or_default();
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *le {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn in_flight(&self, delta: isize) {
+        self.in_flight.fetch_add(delta, Ordering::SeqCst);
+    }
+
+    fn connections(&self, delta: isize) {
+        self.connections.fetch_add(delta, Ordering::SeqCst);
+    }
+
+    fn bytes_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, bytes: usize) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn queue_depth(&self, pool: &str, depth: usize) {
+        let mut collected = self.collected.lock().unwrap();
+        collected.queue_depths.insert(pool.to_owned(), depth);
+    }
+
+    fn render(&self) -> String {
+        let p = &self.prefix;
+        let collected = self.collected.lock().unwrap();
+        let mut out = String::new();
+
+        writeln!(out, "# TYPE {}_calls_total counter", p).unwrap();
+        for ((fname, result), count) in &collected.calls {
+            writeln!(
+                out,
+                "{}_calls_total{{function=\"{}\",result=\"{}\"}} {}",
+                p,
+                escape(fname),
+                result,
+                count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "# TYPE {}_call_duration_seconds histogram", p).unwrap();
+        for ((fname, result), histogram) in &collected.latencies {
+            let labels = format!("function=\"{}\",result=\"{}\"", escape(fname), result);
+            for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(
+                    out,
+                    "{}_call_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    p, labels, le, count
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}_call_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                p, labels, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}_call_duration_seconds_sum{{{}}} {}",
+                p, labels, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}_call_duration_seconds_count{{{}}} {}",
+                p, labels, histogram.count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "# TYPE {}_bytes_read_total counter", p).unwrap();
+        writeln!(
+            out,
+            "{}_bytes_read_total {}",
+            p,
+            self.bytes_read.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {}_bytes_written_total counter", p).unwrap();
+        writeln!(
+            out,
+            "{}_bytes_written_total {}",
+            p,
+            self.bytes_written.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        writeln!(out, "# TYPE {}_in_flight gauge", p).unwrap();
+        writeln!(
+            out,
+            "{}_in_flight {}",
+            p,
+            self.in_flight.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {}_connections gauge", p).unwrap();
+        writeln!(
+            out,
+            "{}_connections {}",
+            p,
+            self.connections.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {}_queue_depth gauge", p).unwrap();
+        for (pool, depth) in &collected.queue_depths {
+            writeln!(
+                out,
+                "{}_queue_depth{{pool=\"{}\"}} {}",
+                p,
+                escape(pool),
+                depth
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// The result code of a response or error packet.
+pub fn result_code(packet: &SlackerPacket) -> Option<u8> {
+    match *packet {
+        SlackerPacket(_, SlackerPacketBody::Response(ref r)) => Some(r.result_code),
+        SlackerPacket(_, SlackerPacketBody::Error(ref e)) => Some(e.result_code),
+        _ => None,
+    }
+}
+
+pub struct Metered<S> {
+    inner: S,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, metrics: Arc<dyn Metrics>) -> Metered<S> {
+        Metered { inner, metrics }
+    }
+}
+
+impl<S> Service for Metered<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let fname = match req {
+            SlackerPacket(_, SlackerPacketBody::Request(ref sreq)) => sreq.fname.clone(),
+            _ => return Box::new(self.inner.call(req)),
+        };
+
+        let metrics = self.metrics.clone();
+        let started = Instant::now();
+        metrics.in_flight(1);
+        Box::new(self.inner.call(req).then(move |r| {
+            metrics.in_flight(-1);
+            metrics.call(
+                &fname,
+                r.as_ref().ok().and_then(result_code),
+                started.elapsed(),
+            );
+            r
+        }))
+    }
+}
+
+fn render(metrics: &[Arc<dyn Metrics>]) -> String {
+    let mut out = String::new();
+    for m in metrics {
+        out.push_str(&m.render());
+    }
+    out
+}
+
+/// A minimal HTTP endpoint answering every request with the text exposition
+/// of `metrics`. Runs until the returned future is dropped.
+pub fn metrics_endpoint(
+    addr: &SocketAddr,
+    metrics: Vec<Arc<dyn Metrics>>,
+    handle: &Handle,
+) -> io::Result<Box<dyn Future<Item = (), Error = io::Error>>> {
+    let listener = TcpListener::bind(addr, handle)?;
+    info!("serving metrics on {}", listener.local_addr()?);
+    let handle = handle.clone();
+    Ok(Box::new(listener.incoming().for_each(
+        move |(socket, peer)| {
+            let metrics = metrics.clone();
+            handle.spawn(
+                read(socket, vec![0; 1024])
+                    .and_then(move |(socket, _, _)| {
+                        let body = render(&metrics);
+                        let response = format!(
+                            "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        write_all(socket, response.into_bytes())
+                    })
+                    .map(|_| ())
+                    .map_err(move |e| debug!("metrics request from {} failed: {}", peer, e)),
+            );
+            Ok(())
+        },
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use codecs::SlackerCodec;
+    use tokio_codec::{Decoder, Encoder};
+
+    #[test]
+    fn latencies_are_kept_per_result_code() {
+        let metrics = PrometheusMetrics::new("test");
+        metrics.call(
+            "demo/echo",
+            Some(RESULT_CODE_SUCCESS),
+            Duration::from_millis(1),
+        );
+        metrics.call(
+            "demo/echo",
+            Some(RESULT_CODE_NOT_FOUND),
+            Duration::from_secs(1),
+        );
+
+        let out = metrics.render();
+        assert!(
+            out.contains("test_call_duration_seconds_count{function=\"demo/echo\",result=\"0\"} 1")
+        );
+        assert!(out
+            .contains("test_call_duration_seconds_count{function=\"demo/echo\",result=\"11\"} 1"));
+    }
+
+    #[test]
+    fn codec_counts_bytes_of_its_own_connection() {
+        let metrics = Arc::new(PrometheusMetrics::new("test"));
+        let mut codec = SlackerCodec::new(Some(metrics.clone()));
+        let ping = SlackerPacket(
+            SlackerPacketHeader {
+                version: PROTOCOL_VERSION_5,
+                serial_id: 1,
+                packet_type: PACKET_TYPE_PING,
+            },
+            SlackerPacketBody::Ping,
+        );
+        let mut buf = BytesMut::new();
+        codec.encode((1, ping), &mut buf).unwrap();
+        let written = buf.len();
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        let out = metrics.render();
+        assert!(out.contains(&format!("test_bytes_written_total {}", written)));
+        assert!(out.contains(&format!("test_bytes_read_total {}", written)));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::mpsc::Sender;
+use serde::Serialize;
+use serde_json;
+use serde_json::value::Value as Json;
+
+use parser::*;
+use service::error_response;
+
+// older notifications are dropped beyond this
+const MAX_PENDING: usize = 1024;
+
+struct Subscriptions {
+    // sent before the client subscribed
+    queue: VecDeque<Json>,
+    // of the requests subscribing, whose serial ids the notifications take
+    headers: Vec<SlackerPacketHeader>,
+    push: Option<Sender<SlackerPacket>>,
+    closed: bool,
+}
+
+/// Sends notifications to a connection, which its client receives from
+/// `Client::notifications`. Clones may be kept after the request that
+/// received one, e.g. to publish updates of a subscription.
+#[derive(Clone)]
+pub struct Notifier {
+    subscriptions: Arc<Mutex<Subscriptions>>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed.")
+}
+
+fn notification(header: SlackerPacketHeader, notifications: &[Json]) -> io::Result<SlackerPacket> {
+    let mut resp_header = header;
+    resp_header.packet_type = PACKET_TYPE_RESPONSE;
+    let body = SlackerPacketBody::Response(SlackerResponsePacket {
+        result_code: RESULT_CODE_SUCCESS,
+        content_type: JSON_CONTENT_TYPE,
+        data: serde_json::to_vec(notifications)?,
+        extensions: vec![SlackerExtension {
+            ext_id: EXTENSION_ID_NOTIFICATIONS,
+            data: Vec::new(),
+        }],
+    });
+    Ok(SlackerPacket(resp_header, body))
+}
+
+impl Subscriptions {
+    fn push(&mut self, packet: SlackerPacket) -> io::Result<()> {
+        let result = match self.push {
+            Some(ref mut push) => push.try_send(packet),
+            None => return Err(closed()),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.is_full() => {
+                warn!("too many notifications waiting to be written, dropping one");
+                Ok(())
+            }
+            Err(_) => {
+                self.close();
+                Err(closed())
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.push = None;
+        self.headers.clear();
+        self.queue.clear();
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        Notifier {
+            subscriptions: Arc::new(Mutex::new(Subscriptions {
+                queue: VecDeque::new(),
+                headers: Vec::new(),
+                push: None,
+                closed: false,
+            })),
+        }
+    }
+
+    /// Writes notifications with `push`, which servers set up for each
+    /// connection.
+    pub fn attach(&self, push: Sender<SlackerPacket>) {
+        self.subscriptions.lock().unwrap().push = Some(push);
+    }
+
+    /// Fails once the connection is closed. Notifications are kept until
+    /// the client subscribes.
+    pub fn notify<S: Serialize>(&self, notification: &S) -> io::Result<()> {
+        let value = serde_json::to_value(notification)?;
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.closed {
+            return Err(closed());
+        }
+        if subscriptions.headers.is_empty() {
+            if subscriptions.queue.len() >= MAX_PENDING {
+                warn!("too many pending notifications, dropping the oldest");
+                subscriptions.queue.pop_front();
+            }
+            subscriptions.queue.push_back(value);
+            return Ok(());
+        }
+        let values = [value];
+        let headers = subscriptions.headers.clone();
+        for header in headers {
+            subscriptions.push(self::notification(header, &values)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.subscriptions.lock().unwrap().closed
+    }
+
+    pub fn close(&self) {
+        self.subscriptions.lock().unwrap().close();
+    }
+
+    /// Subscribes the client to the notifications of the connection. They
+    /// are pushed under the serial id of the request, starting with a packet
+    /// of those kept so far, possibly none, which answers it.
+    pub fn subscribe(&self, header: SlackerPacketHeader) -> SlackerPacket {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.closed || subscriptions.push.is_none() {
+            return error_response(header, RESULT_CODE_NOT_FOUND);
+        }
+        let queued: Vec<Json> = subscriptions.queue.drain(..).collect();
+        subscriptions.headers.push(header);
+        // pushed like the notifications to keep them in order, so the
+        // response isn't written
+        let pushed = notification(header, &queued).and_then(|packet| subscriptions.push(packet));
+        match pushed.and_then(|()| notification(header, &[])) {
+            Ok(SlackerPacket(mut answer, body)) => {
+                answer.packet_type = PACKET_TYPE_NO_RESPONSE;
+                SlackerPacket(answer, body)
+            }
+            Err(e) => {
+                warn!("failed to subscribe to notifications: {}", e);
+                error_response(header, RESULT_CODE_NOT_FOUND)
+            }
+        }
+    }
+
+    /// Ends the subscription of request `serial_id`.
+    pub fn unsubscribe(&self, serial_id: i32) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions
+            .headers
+            .retain(|header| header.serial_id != serial_id);
+    }
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Notifier")
+    }
+}
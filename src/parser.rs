@@ -1,8 +1,27 @@
-use nom::{be_i32, be_u16, be_u32, be_u8};
+use nom::{be_i16, be_i32, be_u16, be_u32, be_u8};
 
 pub const PROTOCOL_VERSION_5: u8 = 5;
+pub const PROTOCOL_VERSION_6: u8 = 6;
 pub const RESULT_CODE_SUCCESS: u8 = 0;
 pub const RESULT_CODE_NOT_FOUND: u8 = 11;
+pub const RESULT_CODE_ACL_REJECT: u8 = 16;
+pub const RESULT_CODE_SERVER_BUSY: u8 = 17;
+
+pub const EXTENSION_ID_AUTH_TOKEN: i16 = -1;
+pub const EXTENSION_ID_TRACE_CONTEXT: i16 = -2;
+pub const EXTENSION_ID_STREAM: i16 = -3;
+// asks for the notifications sent to the connection
+pub const EXTENSION_ID_NOTIFICATIONS: i16 = -4;
+// marks a one-way call, which gets no response
+pub const EXTENSION_ID_ONE_WAY: i16 = -5;
+
+// operations of the stream extension, followed by the stream id: chunks
+// are sent under the serial id of the request opening the stream, which
+// is answered once the stream ends
+pub const STREAM_OPEN: u8 = 0;
+pub const STREAM_CANCEL: u8 = 2;
+pub const STREAM_CHUNK: u8 = 3;
+pub const STREAM_END: u8 = 4;
 
 pub const JSON_CONTENT_TYPE: u8 = 1;
 
@@ -14,6 +33,11 @@ pub const PACKET_TYPE_ERROR: u8 = 4;
 pub const PACKET_TYPE_INSPECT_REQUEST: u8 = 7;
 pub const PACKET_TYPE_INSPECT_RESPONSE: u8 = 8;
 pub const PACKET_TYPE_INTERRUPT: u8 = 9;
+// never written: the codec skips responses of this type
+pub const PACKET_TYPE_NO_RESPONSE: u8 = 0xff;
+
+pub const INSPECT_TYPE_FUNCTIONS: u8 = 0x10;
+pub const INSPECT_TYPE_META: u8 = 0x11;
 
 #[derive(Debug, Copy, Clone)]
 pub struct SlackerPacketHeader {
@@ -33,11 +57,54 @@ named!(
     )
 );
 
+/// Protocol v6 requests and responses end with a list of extensions.
+#[derive(Debug, Clone)]
+pub struct SlackerExtension {
+    pub ext_id: i16,
+    pub data: Vec<u8>,
+}
+
+pub fn find_extension(extensions: &[SlackerExtension], ext_id: i16) -> Option<&[u8]> {
+    extensions
+        .iter()
+        .find(|ext| ext.ext_id == ext_id)
+        .map(|ext| ext.data.as_ref())
+}
+
+/// A stream is identified by the serial id of the request opening it.
+pub fn stream_extension(op: u8, stream_id: i32) -> SlackerExtension {
+    let id = stream_id as u32;
+    SlackerExtension {
+        ext_id: EXTENSION_ID_STREAM,
+        data: vec![
+            op,
+            (id >> 24) as u8,
+            (id >> 16) as u8,
+            (id >> 8) as u8,
+            id as u8,
+        ],
+    }
+}
+
+/// The operation and stream id of a stream extension.
+pub fn stream_op(extensions: &[SlackerExtension]) -> Option<(u8, i32)> {
+    match find_extension(extensions, EXTENSION_ID_STREAM) {
+        Some(data) if data.len() == 5 => {
+            let id = data[1..]
+                .iter()
+                .fold(0u32, |id, b| (id << 8) | u32::from(*b));
+            Some((data[0], id as i32))
+        }
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub struct SlackerRequestPacket {
     pub content_type: u8,
     pub fname: String,
     pub arguments: Vec<u8>,
+    pub extensions: Vec<SlackerExtension>,
 }
 
 #[derive(Debug)]
@@ -45,6 +112,7 @@ pub struct SlackerResponsePacket {
     pub content_type: u8,
     pub result_code: u8,
     pub data: Vec<u8>,
+    pub extensions: Vec<SlackerExtension>,
 }
 
 #[derive(Debug)]
@@ -80,94 +148,158 @@ pub enum SlackerPacketBody {
     Pong,
 }
 
-named!(slacker_request <&[u8], SlackerPacketBody>,
-       do_parse!( ct: be_u8 >>
-                  fname_len: be_u16 >>
-                  fname: take_str!(fname_len) >>
-                  args_len: be_u32 >>
-                  args: take!(args_len) >>
-                  (
-                      SlackerPacketBody::Request(
-                          SlackerRequestPacket {
-                              content_type: ct,
-                              fname: fname.to_owned(),
-                              arguments: args.into()
-                          }
-                      )
-                  )));
-
-named!(slacker_response <&[u8], SlackerPacketBody>,
-       do_parse!(ct: be_u8 >>
-                 rt: be_u8 >>
-                 data_len: be_u32 >>
-                 data: take!(data_len) >>
-                 (
-                     SlackerPacketBody::Response(
-                         SlackerResponsePacket {
-                             content_type: ct,
-                             result_code: rt,
-                             data: data.into()
-                         })
-                 )));
+named!(slacker_extension <&[u8], SlackerExtension>,
+do_parse!(ext_id: be_i16 >>
+          data_len: be_u32 >>
+          data: take!(data_len) >>
+          (
+              SlackerExtension {
+                  ext_id,
+                  data: data.into()
+              }
+          )));
+
+named_args!(slacker_extensions(version: u8) <&[u8], Vec<SlackerExtension>>,
+do_parse!(exts: cond!(version >= PROTOCOL_VERSION_6,
+                      do_parse!(n: be_u8 >>
+                                exts: count!(slacker_extension, n as usize) >>
+                                (exts))) >>
+          (exts.unwrap_or_default())
+));
+
+named_args!(slacker_request(version: u8) <&[u8], SlackerPacketBody>,
+do_parse!( ct: be_u8 >>
+           fname_len: be_u16 >>
+           fname: take_str!(fname_len) >>
+           args_len: be_u32 >>
+           args: take!(args_len) >>
+           exts: call!(slacker_extensions, version) >>
+           (
+               SlackerPacketBody::Request(
+                   SlackerRequestPacket {
+                       content_type: ct,
+                       fname: fname.to_owned(),
+                       arguments: args.into(),
+                       extensions: exts
+                   }
+               )
+           )));
+
+named_args!(slacker_response(version: u8) <&[u8], SlackerPacketBody>,
+do_parse!(ct: be_u8 >>
+          rt: be_u8 >>
+          data_len: be_u32 >>
+          data: take!(data_len) >>
+          exts: call!(slacker_extensions, version) >>
+          (
+              SlackerPacketBody::Response(
+                  SlackerResponsePacket {
+                      content_type: ct,
+                      result_code: rt,
+                      data: data.into(),
+                      extensions: exts
+                  })
+          )));
 
 named!(slacker_error <&[u8], SlackerPacketBody>,
-       do_parse!(rt: be_u8 >>
-                 (
-                     SlackerPacketBody::Error(
-                         SlackerErrorPacket {
-                             result_code: rt
-                         }
-                     )
-                 )
-       ));
+do_parse!(rt: be_u8 >>
+          (
+              SlackerPacketBody::Error(
+                  SlackerErrorPacket {
+                      result_code: rt
+                  }
+              )
+          )
+));
 
 named!(slacker_inspect_req <&[u8], SlackerPacketBody>,
-       do_parse!(it: be_u8 >>
-                 data_len: be_u16 >>
-                 data: take!(data_len) >>
-                 (
-                     SlackerPacketBody::InspectRequest(
-                         SlackerInspectRequestPacket {
-                             inspect_type: it,
-                             data: data.into()
-                         }
-                     )
-                 )));
+do_parse!(it: be_u8 >>
+          data_len: be_u16 >>
+          data: take!(data_len) >>
+          (
+              SlackerPacketBody::InspectRequest(
+                  SlackerInspectRequestPacket {
+                      inspect_type: it,
+                      data: data.into()
+                  }
+              )
+          )));
 
 named!(slacker_inspect_resp <&[u8], SlackerPacketBody>,
-       do_parse!(data_len: be_u16 >>
-                 data: take!(data_len) >>
-                 (
-                     SlackerPacketBody::InspectResponse(
-                         SlackerInspectResponsePacket {
-                             data: data.into()
-                         })
-                 )
-       ));
+do_parse!(data_len: be_u16 >>
+          data: take!(data_len) >>
+          (
+              SlackerPacketBody::InspectResponse(
+                  SlackerInspectResponsePacket {
+                      data: data.into()
+                  })
+          )
+));
 
 named!(slacker_interrupt <&[u8], SlackerPacketBody>,
-       do_parse!(req_id: be_i32 >>
-                 (
-                     SlackerPacketBody::Interrupt(
-                         SlackerInterruptPacket {
-                             req_id: req_id
-                         })
-                 )
-       ));
+do_parse!(req_id: be_i32 >>
+          (
+              SlackerPacketBody::Interrupt(
+                  SlackerInterruptPacket {
+                      req_id
+                  })
+          )
+));
 
 #[derive(Debug)]
 pub struct SlackerPacket(pub SlackerPacketHeader, pub SlackerPacketBody);
 
 named!(pub slacker_all <&[u8], SlackerPacket>,
-       do_parse!(header: slacker_header >>
-                 body: switch!(value!(header.packet_type),
-                               PACKET_TYPE_REQUEST => call!(slacker_request) |
-                               PACKET_TYPE_RESPONSE => call!(slacker_response) |
-                               PACKET_TYPE_PING => value!(SlackerPacketBody::Ping) |
-                               PACKET_TYPE_PONG => value!(SlackerPacketBody::Pong) |
-                               PACKET_TYPE_ERROR => call!(slacker_error) |
-                               PACKET_TYPE_INSPECT_REQUEST => call!(slacker_inspect_req) |
-                               PACKET_TYPE_INSPECT_RESPONSE => call!(slacker_inspect_resp) |
-                               PACKET_TYPE_INTERRUPT => call!(slacker_interrupt)) >>
-                 (SlackerPacket(header, body))
-       ));
+do_parse!(header: slacker_header >>
+          body: switch!(value!(header.packet_type),
+                        PACKET_TYPE_REQUEST => call!(slacker_request, header.version) |
+                        PACKET_TYPE_RESPONSE => call!(slacker_response, header.version) |
+                        PACKET_TYPE_PING => value!(SlackerPacketBody::Ping) |
+                        PACKET_TYPE_PONG => value!(SlackerPacketBody::Pong) |
+                        PACKET_TYPE_ERROR => call!(slacker_error) |
+                        PACKET_TYPE_INSPECT_REQUEST => call!(slacker_inspect_req) |
+                        PACKET_TYPE_INSPECT_RESPONSE => call!(slacker_inspect_resp) |
+                        PACKET_TYPE_INTERRUPT => call!(slacker_interrupt)) >>
+          (SlackerPacket(header, body))
+));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_extensions_keep_the_stream_id() {
+        for &stream_id in &[0, 1, 0x0102_0304, -1, i32::MAX, i32::MIN] {
+            let extensions = vec![stream_extension(STREAM_CHUNK, stream_id)];
+            assert_eq!(stream_op(&extensions), Some((STREAM_CHUNK, stream_id)));
+        }
+    }
+
+    #[test]
+    fn malformed_stream_extensions_are_ignored() {
+        let extensions = vec![SlackerExtension {
+            ext_id: EXTENSION_ID_STREAM,
+            data: vec![STREAM_OPEN],
+        }];
+        assert_eq!(stream_op(&extensions), None);
+        assert_eq!(stream_op(&[]), None);
+    }
+
+    #[test]
+    fn v6_responses_parse_their_extensions() {
+        let mut data = vec![PROTOCOL_VERSION_6, 0, 0, 0, 9, PACKET_TYPE_RESPONSE];
+        data.extend_from_slice(&[JSON_CONTENT_TYPE, RESULT_CODE_SUCCESS, 0, 0, 0, 2]);
+        data.extend_from_slice(b"[]");
+        data.push(1);
+        data.extend_from_slice(&[0xff, 0xfd, 0, 0, 0, 5, STREAM_END, 0, 0, 0, 9]);
+        match slacker_all(&data) {
+            Ok((rest, SlackerPacket(header, SlackerPacketBody::Response(resp)))) => {
+                assert!(rest.is_empty());
+                assert_eq!(header.serial_id, 9);
+                assert_eq!(resp.data, b"[]".to_vec());
+                assert_eq!(stream_op(&resp.extensions), Some((STREAM_END, 9)));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
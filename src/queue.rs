@@ -0,0 +1,396 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::future::ok;
+use futures::sync::oneshot::{self, Sender};
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use futures_cpupool::CpuPool;
+
+use metrics::Metrics;
+use parser::*;
+use service::error_response;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Reply `RESULT_CODE_SERVER_BUSY` to new jobs while the queue is full.
+    Reject,
+    /// Hold new jobs back until there is room. Once a connection has too
+    /// many requests waiting the protocol stops reading from its socket.
+    Block,
+    /// Reply `RESULT_CODE_SERVER_BUSY` to the oldest queued job to make room.
+    DropOldest,
+}
+
+type Job = Box<dyn FnOnce() -> io::Result<SlackerPacket> + Send>;
+
+// A job waiting for a thread.
+struct Pending {
+    id: usize,
+    header: SlackerPacketHeader,
+    job: Job,
+    done: Sender<io::Result<SlackerPacket>>,
+}
+
+struct QueueState {
+    next_id: usize,
+    // jobs holding a thread
+    running: usize,
+    queued: VecDeque<Pending>,
+    // jobs waiting for room under `QueuePolicy::Block`, first come first served
+    waiters: VecDeque<(usize, Task)>,
+    metrics: Option<(String, Arc<dyn Metrics>)>,
+}
+
+impl QueueState {
+    fn id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+
+    fn report(&self) {
+        if let Some((ref name, ref metrics)) = self.metrics {
+            metrics.queue_depth(name, self.queued.len());
+        }
+    }
+
+    // Wakes as many waiters as there is room for.
+    fn wake(&self, capacity: usize) {
+        let room = capacity.saturating_sub(self.queued.len());
+        for (_, waiter) in self.waiters.iter().take(room) {
+            waiter.notify();
+        }
+    }
+}
+
+struct QueueInner {
+    pool: CpuPool,
+    threads: usize,
+    capacity: usize,
+    policy: QueuePolicy,
+    state: Mutex<QueueState>,
+}
+
+/// Jobs waiting for a thread of a `ThreadPoolServer`, shared by all of its
+/// connections. Jobs are handed to the pool only once a thread is free.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<QueueInner>,
+}
+
+impl JobQueue {
+    pub fn new(threads: usize, capacity: usize, policy: QueuePolicy) -> JobQueue {
+        assert!(capacity > 0, "queue capacity must be positive");
+        JobQueue {
+            inner: Arc::new(QueueInner {
+                pool: CpuPool::new(threads),
+                threads,
+                capacity,
+                policy,
+                state: Mutex::new(QueueState {
+                    next_id: 0,
+                    running: 0,
+                    queued: VecDeque::new(),
+                    waiters: VecDeque::new(),
+                    metrics: None,
+                }),
+            }),
+        }
+    }
+
+    /// Number of jobs waiting for a thread.
+    pub fn depth(&self) -> usize {
+        self.inner.state.lock().unwrap().queued.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    /// Reports the depth of this queue as `name` whenever it changes.
+    pub fn report_to(&self, name: &str, metrics: Arc<dyn Metrics>) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.metrics = Some((name.to_owned(), metrics));
+        state.report();
+    }
+
+    pub fn spawn<F>(
+        &self,
+        header: SlackerPacketHeader,
+        f: F,
+    ) -> Box<dyn Future<Item = SlackerPacket, Error = io::Error>>
+    where
+        F: FnOnce() -> io::Result<SlackerPacket> + Send + 'static,
+    {
+        if self.inner.policy == QueuePolicy::Block {
+            return Box::new(
+                SlotWait {
+                    queue: self.clone(),
+                    job: Some((header, Box::new(f))),
+                    waiter: None,
+                }
+                .flatten(),
+            );
+        }
+
+        let mut state = self.inner.state.lock().unwrap();
+        if state.queued.len() >= self.inner.capacity {
+            if self.inner.policy != QueuePolicy::DropOldest {
+                debug!("queue full, rejecting request {}", header.serial_id);
+                return Box::new(ok(error_response(header, RESULT_CODE_SERVER_BUSY)));
+            }
+            if let Some(dropped) = state.queued.pop_front() {
+                debug!("queue full, dropping request {}", dropped.header.serial_id);
+                let busy = error_response(dropped.header, RESULT_CODE_SERVER_BUSY);
+                let _ = dropped.done.send(Ok(busy));
+            }
+        }
+        self.submit(&mut state, header, Box::new(f))
+    }
+
+    // Runs the job on a free thread, or queues it until one frees.
+    fn submit(
+        &self,
+        state: &mut QueueState,
+        header: SlackerPacketHeader,
+        job: Job,
+    ) -> Box<dyn Future<Item = SlackerPacket, Error = io::Error>> {
+        let (done, rx) = oneshot::channel();
+        let id = state.id();
+        let pending = Pending {
+            id,
+            header,
+            job,
+            done,
+        };
+        if state.running < self.inner.threads {
+            state.running += 1;
+            self.start(pending);
+        } else {
+            state.queued.push_back(pending);
+            state.report();
+        }
+
+        // A job whose response is no longer awaited must leave the queue too.
+        let guard = Queued(self.clone(), id);
+        Box::new(rx.then(move |r| {
+            drop(guard);
+            match r {
+                Ok(resp) => resp,
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Job failed.")),
+            }
+        }))
+    }
+
+    fn start(&self, pending: Pending) {
+        let queue = self.clone();
+        self.inner
+            .pool
+            .spawn_fn(move || {
+                // frees the thread for the next job even if this one panics
+                let _running = Running(queue);
+                let _ = pending.done.send((pending.job)());
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+
+    // Hands the thread of a finished job to the next queued one.
+    fn finish(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        match state.queued.pop_front() {
+            Some(next) => {
+                state.report();
+                state.wake(self.inner.capacity);
+                self.start(next);
+            }
+            None => state.running -= 1,
+        }
+    }
+
+    fn dequeue(&self, id: usize) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(pos) = state.queued.iter().position(|pending| pending.id == id) {
+            state.queued.remove(pos);
+            state.report();
+            state.wake(self.inner.capacity);
+        }
+    }
+}
+
+struct Queued(JobQueue, usize);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.dequeue(self.1);
+    }
+}
+
+struct Running(JobQueue);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+// Submits the job as soon as there is room and no earlier job is waiting.
+struct SlotWait {
+    queue: JobQueue,
+    job: Option<(SlackerPacketHeader, Job)>,
+    waiter: Option<usize>,
+}
+
+impl Future for SlotWait {
+    type Item = Box<dyn Future<Item = SlackerPacket, Error = io::Error>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let mut state = self.queue.inner.state.lock().unwrap();
+        let room = self.queue.inner.capacity.saturating_sub(state.queued.len());
+        let pos = match self.waiter {
+            Some(waiter) => state
+                .waiters
+                .iter()
+                .position(|&(id, _)| id == waiter)
+                .expect("waiter left the queue"),
+            None => state.waiters.len(),
+        };
+        if pos < room {
+            if self.waiter.take().is_some() {
+                state.waiters.remove(pos);
+            }
+            let (header, job) = self.job.take().expect("polled after completion");
+            return Ok(Async::Ready(self.queue.submit(&mut state, header, job)));
+        }
+
+        match self.waiter {
+            Some(_) => state.waiters[pos].1 = task::current(),
+            None => {
+                let id = state.id();
+                state.waiters.push_back((id, task::current()));
+                self.waiter = Some(id);
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for SlotWait {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter {
+            let mut state = self.queue.inner.state.lock().unwrap();
+            state.waiters.retain(|&(id, _)| id != waiter);
+            // the room it was woken for goes to the next one
+            state.wake(self.queue.inner.capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use metrics::result_code;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn header(serial_id: i32) -> SlackerPacketHeader {
+        SlackerPacketHeader {
+            version: PROTOCOL_VERSION_5,
+            serial_id,
+            packet_type: PACKET_TYPE_REQUEST,
+        }
+    }
+
+    fn done(serial_id: i32) -> io::Result<SlackerPacket> {
+        Ok(error_response(header(serial_id), RESULT_CODE_SUCCESS))
+    }
+
+    #[test]
+    fn blocked_jobs_wait_their_turn() {
+        let queue = JobQueue::new(1, 1, QueuePolicy::Block);
+        let (release, released) = mpsc::channel::<()>();
+        let mut jobs = vec![queue.spawn(header(0), move || {
+            released.recv().unwrap();
+            done(0)
+        })];
+        // the first job leaves the queue once it holds the only thread
+        while queue.depth() > 0 {
+            thread::yield_now();
+        }
+        for i in 1..5 {
+            jobs.push(queue.spawn(header(i), move || done(i)));
+        }
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release.send(()).unwrap();
+        });
+
+        let results = join_all(jobs).wait().unwrap();
+        assert!(results
+            .iter()
+            .all(|r| result_code(r) == Some(RESULT_CODE_SUCCESS)));
+        assert!(queue.inner.state.lock().unwrap().waiters.is_empty());
+    }
+
+    #[test]
+    fn full_queue_rejects_under_reject_policy() {
+        let queue = JobQueue::new(1, 1, QueuePolicy::Reject);
+        let (release, released) = mpsc::channel::<()>();
+        let first = queue.spawn(header(0), move || {
+            released.recv().unwrap();
+            done(0)
+        });
+        while queue.depth() > 0 {
+            thread::yield_now();
+        }
+        let queued = queue.spawn(header(1), || done(1));
+        let rejected = queue.spawn(header(2), || done(2)).wait().unwrap();
+        assert_eq!(result_code(&rejected), Some(RESULT_CODE_SERVER_BUSY));
+
+        release.send(()).unwrap();
+        let results = join_all(vec![first, queued]).wait().unwrap();
+        assert!(results
+            .iter()
+            .all(|r| result_code(r) == Some(RESULT_CODE_SUCCESS)));
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_job_under_drop_oldest_policy() {
+        let queue = JobQueue::new(1, 1, QueuePolicy::DropOldest);
+        let (release, released) = mpsc::channel::<()>();
+        let first = queue.spawn(header(0), move || {
+            released.recv().unwrap();
+            done(0)
+        });
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let ran_by_second = ran.clone();
+        let second = queue.spawn(header(1), move || {
+            ran_by_second.lock().unwrap().push(1);
+            done(1)
+        });
+        let ran_by_third = ran.clone();
+        let third = queue.spawn(header(2), move || {
+            ran_by_third.lock().unwrap().push(2);
+            done(2)
+        });
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(
+            result_code(&second.wait().unwrap()),
+            Some(RESULT_CODE_SERVER_BUSY)
+        );
+
+        release.send(()).unwrap();
+        let results = join_all(vec![first, third]).wait().unwrap();
+        assert!(results
+            .iter()
+            .all(|r| result_code(r) == Some(RESULT_CODE_SUCCESS)));
+        // the dropped job never reached the pool
+        assert_eq!(*ran.lock().unwrap(), vec![2]);
+        assert_eq!(queue.depth(), 0);
+    }
+}
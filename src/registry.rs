@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+/// Functions served by a server. Clones share the same functions, so a
+/// handle can register and unregister them while the server is running.
+pub struct FunctionRegistry<F> {
+    funcs: Arc<RwLock<BTreeMap<String, Arc<F>>>>,
+    idempotent: Arc<RwLock<BTreeSet<String>>>,
+    watchers: Arc<Mutex<Vec<UnboundedSender<()>>>>,
+}
+
+impl<F> Clone for FunctionRegistry<F> {
+    fn clone(&self) -> Self {
+        FunctionRegistry {
+            funcs: self.funcs.clone(),
+            idempotent: self.idempotent.clone(),
+            watchers: self.watchers.clone(),
+        }
+    }
+}
+
+impl<F> From<BTreeMap<String, F>> for FunctionRegistry<F> {
+    fn from(funcs: BTreeMap<String, F>) -> Self {
+        FunctionRegistry {
+            funcs: Arc::new(RwLock::new(
+                funcs.into_iter().map(|(k, f)| (k, Arc::new(f))).collect(),
+            )),
+            idempotent: Arc::new(RwLock::new(BTreeSet::new())),
+            watchers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<F> Default for FunctionRegistry<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> FunctionRegistry<F> {
+    pub fn new() -> Self {
+        FunctionRegistry::from(BTreeMap::new())
+    }
+
+    /// Adds a function, replacing any registered under the same name.
+    pub fn register(&self, fname: &str, f: F) {
+        self.funcs
+            .write()
+            .unwrap()
+            .insert(fname.to_owned(), Arc::new(f));
+        self.changed();
+    }
+
+    pub fn unregister(&self, fname: &str) -> bool {
+        let removed = self.funcs.write().unwrap().remove(fname).is_some();
+        self.idempotent.write().unwrap().remove(fname);
+        if removed {
+            self.changed();
+        }
+        removed
+    }
+
+    /// Removes every function of a namespace, returning how many there were.
+    pub fn unregister_namespace(&self, ns_name: &str) -> usize {
+        let mut funcs = self.funcs.write().unwrap();
+        let before = funcs.len();
+        funcs.retain(|fname, _| !in_namespace(fname, ns_name));
+        self.idempotent
+            .write()
+            .unwrap()
+            .retain(|fname| !in_namespace(fname, ns_name));
+        let removed = before - funcs.len();
+        drop(funcs);
+        if removed > 0 {
+            self.changed();
+        }
+        removed
+    }
+
+    /// Lets clients retry calls of `fname`, as told by inspect.
+    pub fn mark_idempotent(&self, fname: &str) {
+        self.idempotent.write().unwrap().insert(fname.to_owned());
+    }
+
+    pub fn is_idempotent(&self, fname: &str) -> bool {
+        self.idempotent.read().unwrap().contains(fname)
+    }
+
+    pub fn get(&self, fname: &str) -> Option<Arc<F>> {
+        self.funcs.read().unwrap().get(fname).cloned()
+    }
+
+    pub fn fnames(&self) -> Vec<String> {
+        self.funcs.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Yields whenever functions are registered or unregistered.
+    pub fn changes(&self) -> UnboundedReceiver<()> {
+        let (tx, rx) = unbounded();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn changed(&self) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| watcher.unbounded_send(()).is_ok());
+    }
+}
+
+pub fn in_namespace(fname: &str, ns_name: &str) -> bool {
+    fname.starts_with(ns_name) && fname[ns_name.len()..].starts_with('/')
+}
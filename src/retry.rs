@@ -0,0 +1,189 @@
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::future::err;
+use futures::Future;
+use tcore::reactor::{Handle, Timeout};
+
+use breaker::CircuitOpen;
+
+/// How calls to idempotent functions are retried after failing.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retryable: Vec<io::ErrorKind>,
+}
+
+impl RetryPolicy {
+    /// Makes up to `max_attempts` attempts, including the first one, waiting
+    /// 100ms before the first retry and twice as long before each next one,
+    /// up to 5s. Connection errors and timeouts are retried, as well as
+    /// calls failed fast by an open circuit, which `ClusterClient` retries
+    /// on another server.
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retryable: vec![
+                io::ErrorKind::BrokenPipe,
+                io::ErrorKind::ConnectionAborted,
+                io::ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::NotConnected,
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::UnexpectedEof,
+            ],
+        }
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Replaces the error kinds worth retrying.
+    pub fn with_retryable(mut self, kinds: Vec<io::ErrorKind>) -> Self {
+        self.retryable = kinds;
+        self
+    }
+
+    pub fn retryable(&self) -> &[io::ErrorKind] {
+        &self.retryable
+    }
+
+    pub fn is_retryable(&self, e: &io::Error) -> bool {
+        self.retryable.contains(&e.kind()) || CircuitOpen::from_error(e).is_some()
+    }
+
+    /// The wait before the retry following the failed `attempt`, counted
+    /// from 0.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let mut backoff = self.initial_backoff;
+        for _ in 0..attempt {
+            backoff *= 2;
+            if backoff >= self.max_backoff {
+                return self.max_backoff;
+            }
+        }
+        backoff
+    }
+}
+
+/// Runs `call` again, as the policy allows, until it succeeds.
+pub fn retry<T, F>(
+    policy: RetryPolicy,
+    handle: Handle,
+    call: F,
+) -> Box<dyn Future<Item = T, Error = io::Error>>
+where
+    T: 'static,
+    F: Fn() -> Box<dyn Future<Item = T, Error = io::Error>> + 'static,
+{
+    attempt(Rc::new(policy), handle, Rc::new(call), 0)
+}
+
+fn attempt<T, F>(
+    policy: Rc<RetryPolicy>,
+    handle: Handle,
+    call: Rc<F>,
+    n: u32,
+) -> Box<dyn Future<Item = T, Error = io::Error>>
+where
+    T: 'static,
+    F: Fn() -> Box<dyn Future<Item = T, Error = io::Error>> + 'static,
+{
+    Box::new(
+        call().or_else(move |e| -> Box<dyn Future<Item = T, Error = io::Error>> {
+            if n + 1 >= policy.max_attempts || !policy.is_retryable(&e) {
+                return Box::new(err(e));
+            }
+            let backoff = policy.backoff(n);
+            debug!("retrying in {:?} after: {}", backoff, e);
+            match Timeout::new(backoff, &handle) {
+                Ok(timer) => {
+                    Box::new(timer.and_then(move |_| attempt(policy, handle, call, n + 1)))
+                }
+                Err(_) => Box::new(err(e)),
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use addr::SlackerAddr;
+    use futures::future::ok;
+    use std::cell::Cell;
+    use tcore::reactor::Core;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy::new(5)
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(300));
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(300));
+        assert_eq!(policy.backoff(10), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn open_circuits_are_retried() {
+        let open = io::Error::new(
+            io::ErrorKind::Other,
+            CircuitOpen {
+                addr: SlackerAddr::Tcp("127.0.0.1:2104".parse().unwrap()),
+                fname: None,
+            },
+        );
+        let policy = RetryPolicy::new(3);
+        assert!(policy.is_retryable(&open));
+        assert!(policy.is_retryable(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(!policy.is_retryable(&io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    fn run(policy: RetryPolicy, failures: Vec<io::ErrorKind>) -> (io::Result<u32>, u32) {
+        let mut core = Core::new().unwrap();
+        let attempts = Rc::new(Cell::new(0));
+        let counted = attempts.clone();
+        let call = retry(policy, core.handle(), move || {
+            let n = counted.get();
+            counted.set(n + 1);
+            match failures.get(n as usize) {
+                Some(kind) => Box::new(err(io::Error::from(*kind))),
+                None => Box::new(ok(n)) as Box<dyn Future<Item = u32, Error = io::Error>>,
+            }
+        });
+        let result = core.run(call);
+        (result, attempts.get())
+    }
+
+    #[test]
+    fn retries_until_the_call_succeeds() {
+        let policy =
+            RetryPolicy::new(3).with_backoff(Duration::from_millis(1), Duration::from_millis(1));
+        let timeouts = vec![io::ErrorKind::TimedOut, io::ErrorKind::TimedOut];
+        let (result, attempts) = run(policy, timeouts);
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn stops_after_the_last_attempt_or_errors_not_worth_retrying() {
+        let policy =
+            RetryPolicy::new(2).with_backoff(Duration::from_millis(1), Duration::from_millis(1));
+        let timeouts = vec![io::ErrorKind::TimedOut; 3];
+        let (result, attempts) = run(policy.clone(), timeouts);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(attempts, 2);
+
+        let (result, attempts) = run(policy, vec![io::ErrorKind::NotFound]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+    }
+}
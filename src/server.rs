@@ -0,0 +1,1359 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{empty, join_all, Either, Shared};
+use futures::sync::mpsc::{self, UnboundedReceiver};
+use futures::unsync::oneshot;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+#[cfg(feature = "tls")]
+use rustls::Session;
+use tcore::net::TcpListener;
+#[cfg(feature = "tls")]
+use tcore::net::TcpStream;
+use tcore::reactor::{Core, Handle, Interval, Timeout};
+use tokio_codec::{Decoder, Framed};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tproto::multiplex::{RequestId, ServerProto};
+use tproto::BindServer;
+use tservice::Service;
+#[cfg(unix)]
+use tuds::UnixListener;
+
+use addr::SlackerAddr;
+use auth::{Authenticated, Authenticator, Authorizer};
+use cluster::{namespaces, Announcement, ClusterRegistry};
+use codecs::SlackerCodec;
+use context::AnyState;
+use interceptor::{ConnectionHook, ConnectionInfo, Intercepted, Interceptor};
+use json::*;
+use limit::{Limit, LimitState, Limited};
+use metrics::{metrics_endpoint, Metered, Metrics};
+use parser::*;
+use queue::{JobQueue, QueuePolicy};
+use registry::FunctionRegistry;
+use serializer::*;
+use service::*;
+#[cfg(feature = "tls")]
+use tls::TlsServerConfig;
+use trace::Traced;
+
+// jobs waiting for a thread of the default pool of a `ThreadPoolServer`
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+pub struct ServerBuilder {
+    config: ServerConfig,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            config: ServerConfig {
+                addrs: Vec::new(),
+                listeners: Vec::new(),
+                nodelay: false,
+                keepalive: None,
+                max_connections: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+                registry: None,
+                advertised_addr: None,
+                interceptors: Vec::new(),
+                authenticator: None,
+                authorizer: None,
+                connection_limit: None,
+                function_limits: BTreeMap::new(),
+                metrics: None,
+                metrics_addr: None,
+                session_factory: None,
+                state: None,
+                connection_hooks: Vec::new(),
+            },
+        }
+    }
+
+    pub fn bind(self, addr: SocketAddr) -> Self {
+        self.bind_addr(SlackerAddr::Tcp(addr))
+    }
+
+    pub fn bind_addr(mut self, addr: SlackerAddr) -> Self {
+        self.config.addrs.push(addr);
+        self
+    }
+
+    /// Serves on an already bound listener, e.g. one passed in by socket
+    /// activation.
+    pub fn listener(mut self, listener: StdTcpListener) -> Self {
+        self.config.listeners.push(Listener::Tcp(listener));
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn unix_listener(mut self, listener: StdUnixListener) -> Self {
+        self.config.listeners.push(Listener::Unix(listener));
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.config.keepalive = keepalive;
+        self
+    }
+
+    /// Connections accepted beyond this limit are closed immediately.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Accepts only TLS connections on TCP listeners.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsServerConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    pub fn registry(mut self, registry: Arc<dyn ClusterRegistry>) -> Self {
+        self.config.registry = Some(registry);
+        self
+    }
+
+    /// The address announced to the registry in place of the listening
+    /// ones, needed when listening on an unspecified address like 0.0.0.0.
+    pub fn advertised_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.advertised_addr = Some(addr);
+        self
+    }
+
+    pub fn interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.config.interceptors.push(interceptor);
+        self
+    }
+
+    /// Requests and inspect requests from unauthenticated connections, or
+    /// for functions the authorizer denies, are rejected with
+    /// `RESULT_CODE_ACL_REJECT`. Function lists leave out the functions the
+    /// authorizer denies.
+    pub fn auth(
+        mut self,
+        authenticator: Arc<dyn Authenticator>,
+        authorizer: Option<Arc<dyn Authorizer>>,
+    ) -> Self {
+        self.config.authenticator = Some(authenticator);
+        self.config.authorizer = authorizer;
+        self
+    }
+
+    /// Applied to each connection separately.
+    pub fn connection_limit(mut self, limit: Limit) -> Self {
+        self.config.connection_limit = Some(limit);
+        self
+    }
+
+    /// Applied to all calls of `fname`, whichever connection they come from.
+    pub fn function_limit(mut self, fname: &str, limit: Limit) -> Self {
+        self.config.function_limits.insert(fname.to_owned(), limit);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// Serves the metrics over HTTP on `addr` while the server is running.
+    pub fn metrics_endpoint(mut self, addr: SocketAddr) -> Self {
+        self.config.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Creates the state of each connection, which handlers taking a
+    /// `RequestContext` reach through `RequestContext::session`, and
+    /// connection hooks through `ConnectionInfo::session`.
+    pub fn session<S, F>(mut self, factory: F) -> Self
+    where
+        S: Any + Send + Sync,
+        F: Fn(&ConnectionInfo) -> S + Send + Sync + 'static,
+    {
+        self.config.session_factory = Some(Arc::new(move |conn| AnyState::new(factory(conn))));
+        self
+    }
+
+    /// Shared by all connections. Handlers reach it as a `State<S>` argument
+    /// or through `RequestContext::state`.
+    pub fn state<S: Any + Send + Sync>(mut self, state: S) -> Self {
+        self.config.state = Some(AnyState::new(state));
+        self
+    }
+
+    pub fn connection_hook(mut self, hook: Arc<dyn ConnectionHook>) -> Self {
+        self.config.connection_hooks.push(hook);
+        self
+    }
+
+    pub fn build(mut self, funcs: BTreeMap<String, JsonRpcFn>) -> io::Result<Server> {
+        self.config.bind()?;
+        Ok(Server {
+            config: self.config,
+            funcs: FunctionRegistry::from(asynchronous(funcs)),
+        })
+    }
+
+    /// Like `build`, also serving handlers taking a context. See
+    /// `Server::new_mixed`.
+    pub fn build_handlers(mut self, funcs: BTreeMap<String, JsonRpcHandler>) -> io::Result<Server> {
+        reject_blocking(&funcs)?;
+        self.config.bind()?;
+        Ok(Server {
+            config: self.config,
+            funcs: FunctionRegistry::from(funcs),
+        })
+    }
+
+    pub fn build_thread_pool(
+        self,
+        funcs: BTreeMap<String, JsonRpcFnSync>,
+        threads: usize,
+    ) -> io::Result<ThreadPoolServer> {
+        self.build_mixed(blocking(funcs), threads)
+    }
+
+    pub fn build_mixed(
+        mut self,
+        funcs: BTreeMap<String, JsonRpcHandler>,
+        threads: usize,
+    ) -> io::Result<ThreadPoolServer> {
+        self.config.bind()?;
+        Ok(ThreadPoolServer::with_config(self.config, funcs, threads))
+    }
+}
+
+enum Listener {
+    Tcp(StdTcpListener),
+    #[cfg(unix)]
+    Unix(StdUnixListener),
+}
+
+impl Listener {
+    fn bind(addr: &SlackerAddr) -> io::Result<Listener> {
+        match *addr {
+            SlackerAddr::Tcp(ref addr) => StdTcpListener::bind(addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            SlackerAddr::Unix(ref path) => {
+                remove_stale_socket(path)?;
+                StdUnixListener::bind(path).map(Listener::Unix)
+            }
+            #[cfg(not(unix))]
+            SlackerAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix domain sockets are not supported on this platform.",
+            )),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Listener> {
+        match *self {
+            Listener::Tcp(ref l) => l.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.try_clone().map(Listener::Unix),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SlackerAddr> {
+        match *self {
+            Listener::Tcp(ref l) => l.local_addr().map(SlackerAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(ref l) => l.local_addr().and_then(|addr| {
+                addr.as_pathname()
+                    .map(|path| SlackerAddr::Unix(path.to_owned()))
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "Unnamed unix socket.")
+                    })
+            }),
+        }
+    }
+}
+
+// Removes a socket file left behind by a server that is gone, which
+// would otherwise make binding fail. Live sockets are left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let is_socket = match fs::metadata(path) {
+        Ok(metadata) => metadata.file_type().is_socket(),
+        Err(_) => return Ok(()),
+    };
+    if is_socket && StdUnixStream::connect(path).is_err() {
+        debug!("removing stale socket {:?}", path);
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Removes the socket files of Unix listeners when serving ends.
+struct SocketFiles(Vec<PathBuf>);
+
+impl Drop for SocketFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("failed to remove socket {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+type SessionFactory = Arc<dyn Fn(&ConnectionInfo) -> AnyState + Send + Sync>;
+
+struct ServerConfig {
+    addrs: Vec<SlackerAddr>,
+    listeners: Vec<Listener>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    max_connections: Option<usize>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsServerConfig>,
+    registry: Option<Arc<dyn ClusterRegistry>>,
+    advertised_addr: Option<SocketAddr>,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    connection_limit: Option<Limit>,
+    function_limits: BTreeMap<String, Limit>,
+    metrics: Option<Arc<dyn Metrics>>,
+    metrics_addr: Option<SocketAddr>,
+    session_factory: Option<SessionFactory>,
+    state: Option<AnyState>,
+    connection_hooks: Vec<Arc<dyn ConnectionHook>>,
+}
+
+impl ServerConfig {
+    fn with_addr(addr: SocketAddr) -> ServerConfig {
+        ServerBuilder::new().bind(addr).config
+    }
+
+    fn bind(&mut self) -> io::Result<()> {
+        for addr in self.addrs.drain(..) {
+            self.listeners.push(Listener::bind(&addr)?);
+        }
+        Ok(())
+    }
+
+    fn local_addrs(&self) -> io::Result<Vec<SlackerAddr>> {
+        let mut addrs = self
+            .listeners
+            .iter()
+            .map(|l| l.local_addr())
+            .collect::<io::Result<Vec<SlackerAddr>>>()?;
+        addrs.extend(self.addrs.iter().cloned());
+        Ok(addrs)
+    }
+}
+
+pub struct Server {
+    config: ServerConfig,
+    funcs: FunctionRegistry<JsonRpcHandler>,
+}
+
+impl Server {
+    pub fn new(addr: SocketAddr, funcs: BTreeMap<String, JsonRpcFn>) -> Self {
+        Server {
+            config: ServerConfig::with_addr(addr),
+            funcs: FunctionRegistry::from(asynchronous(funcs)),
+        }
+    }
+
+    /// Also serves handlers taking a context, e.g. those built with
+    /// `RpcHandler::typed`. Blocking handlers need a thread pool, so they are
+    /// rejected here and belong on a `ThreadPoolServer`.
+    pub fn new_mixed(
+        addr: SocketAddr,
+        funcs: BTreeMap<String, JsonRpcHandler>,
+    ) -> io::Result<Self> {
+        reject_blocking(&funcs)?;
+        Ok(Server {
+            config: ServerConfig::with_addr(addr),
+            funcs: FunctionRegistry::from(funcs),
+        })
+    }
+
+    /// A handle to add and remove functions while the server is running.
+    /// Calls of blocking functions added here fail.
+    pub fn functions(&self) -> FunctionRegistry<JsonRpcHandler> {
+        self.funcs.clone()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SlackerAddr>> {
+        self.config.local_addrs()
+    }
+
+    pub fn serve(&self) {
+        self.serve_until(empty(), Duration::from_secs(0)).unwrap();
+    }
+
+    /// Serves until `shutdown` resolves, then stops accepting connections and
+    /// reading requests. Connections close once their in-flight requests are
+    /// answered, or when `drain_timeout` expires.
+    pub fn serve_until<F>(&self, shutdown: F, drain_timeout: Duration) -> io::Result<()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        let serializer = Arc::new(JsonSerializer);
+        let funcs_ref = self.funcs.clone();
+        serve_until(
+            &self.config,
+            Box::new(move |conn| {
+                Ok(Box::new(Boxed(SlackerService::new(
+                    funcs_ref.clone(),
+                    serializer.clone(),
+                    conn,
+                ))))
+            }),
+            &self.funcs,
+            shutdown,
+            drain_timeout,
+        )
+    }
+}
+
+pub struct ThreadPoolServer {
+    config: ServerConfig,
+    funcs: FunctionRegistry<JsonRpcHandler>,
+    threads: usize,
+    queue: JobQueue,
+    pools: BTreeMap<String, JobQueue>,
+    pool_assignments: Vec<(String, String)>,
+}
+
+impl ThreadPoolServer {
+    pub fn new(addr: SocketAddr, funcs: BTreeMap<String, JsonRpcFnSync>, threads: usize) -> Self {
+        ThreadPoolServer::with_config(ServerConfig::with_addr(addr), blocking(funcs), threads)
+    }
+
+    /// Serves async functions on the reactor and blocking ones on the
+    /// thread pools, all on the same port.
+    pub fn new_mixed(
+        addr: SocketAddr,
+        funcs: BTreeMap<String, JsonRpcHandler>,
+        threads: usize,
+    ) -> Self {
+        ThreadPoolServer::with_config(ServerConfig::with_addr(addr), funcs, threads)
+    }
+
+    fn with_config(
+        config: ServerConfig,
+        funcs: BTreeMap<String, JsonRpcHandler>,
+        threads: usize,
+    ) -> Self {
+        ThreadPoolServer {
+            config,
+            funcs: FunctionRegistry::from(funcs),
+            threads,
+            queue: JobQueue::new(threads, DEFAULT_QUEUE_CAPACITY, QueuePolicy::Block),
+            pools: BTreeMap::new(),
+            pool_assignments: Vec::new(),
+        }
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SlackerAddr>> {
+        self.config.local_addrs()
+    }
+
+    /// Bounds the jobs waiting for a thread of the default pool. By default
+    /// up to 1024 wait, and further jobs are held back under
+    /// `QueuePolicy::Block`.
+    pub fn with_queue(mut self, capacity: usize, policy: QueuePolicy) -> Self {
+        self.queue = JobQueue::new(self.threads, capacity, policy);
+        self
+    }
+
+    /// A handle to add and remove functions while the server is running.
+    pub fn functions(&self) -> FunctionRegistry<JsonRpcHandler> {
+        self.funcs.clone()
+    }
+
+    /// The queue shared by all connections, e.g. to report its depth.
+    pub fn job_queue(&self) -> JobQueue {
+        self.queue.clone()
+    }
+
+    /// Adds a named pool next to the default one, see `assign_pool`.
+    pub fn with_pool(mut self, name: &str, queue: JobQueue) -> Self {
+        self.pools.insert(name.to_owned(), queue);
+        self
+    }
+
+    /// Runs the functions matching `pattern`, a full function name, a
+    /// namespace like `rust.test/*` or `*`, on the named pool. A full function
+    /// name takes precedence over its namespace, which takes precedence over
+    /// `*`.
+    pub fn assign_pool(mut self, pattern: &str, pool: &str) -> Self {
+        self.pool_assignments
+            .push((pattern.to_owned(), pool.to_owned()));
+        self
+    }
+
+    pub fn pool(&self, name: &str) -> Option<JobQueue> {
+        self.pools.get(name).cloned()
+    }
+
+    // Function names come first, then namespaces, then `*`, so the most
+    // specific pattern wins.
+    fn resolve_pools(&self) -> io::Result<Vec<(String, JobQueue)>> {
+        let mut resolved = Vec::new();
+        for (pattern, name) in &self.pool_assignments {
+            let queue = self.pools.get(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown thread pool {}.", name),
+                )
+            })?;
+            resolved.push((pattern.clone(), queue.clone()));
+        }
+        resolved.sort_by_key(|(pattern, _)| match pattern.as_str() {
+            "*" => 2,
+            p if p.ends_with("/*") => 1,
+            _ => 0,
+        });
+        Ok(resolved)
+    }
+
+    pub fn serve(&self) {
+        self.serve_until(empty(), Duration::from_secs(0)).unwrap();
+    }
+
+    /// Like `Server::serve_until`; jobs still queued on the thread pool count
+    /// as in-flight requests.
+    pub fn serve_until<F>(&self, shutdown: F, drain_timeout: Duration) -> io::Result<()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        let serializer = Arc::new(JsonSerializer);
+        let funcs_ref = self.funcs.clone();
+        let queue = self.queue.clone();
+        let pools = Arc::new(self.resolve_pools()?);
+        if let Some(ref metrics) = self.config.metrics {
+            queue.report_to("default", metrics.clone());
+            for (name, pool) in &self.pools {
+                pool.report_to(name, metrics.clone());
+            }
+        }
+        serve_until(
+            &self.config,
+            Box::new(move |conn| {
+                Ok(Box::new(Boxed(
+                    SlackerService::new(funcs_ref.clone(), serializer.clone(), conn)
+                        .with_queues(queue.clone(), pools.clone()),
+                )))
+            }),
+            &self.funcs,
+            shutdown,
+            drain_timeout,
+        )
+    }
+}
+
+fn asynchronous(funcs: BTreeMap<String, JsonRpcFn>) -> BTreeMap<String, JsonRpcHandler> {
+    funcs
+        .into_iter()
+        .map(|(fname, f)| (fname, RpcHandler::Async(f)))
+        .collect()
+}
+
+fn blocking(funcs: BTreeMap<String, JsonRpcFnSync>) -> BTreeMap<String, JsonRpcHandler> {
+    funcs
+        .into_iter()
+        .map(|(fname, f)| (fname, RpcHandler::Blocking(f)))
+        .collect()
+}
+
+fn reject_blocking(funcs: &BTreeMap<String, JsonRpcHandler>) -> io::Result<()> {
+    for (fname, f) in funcs {
+        if let RpcHandler::Blocking(_) | RpcHandler::BlockingWithContext(_) = *f {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Blocking function {} needs a ThreadPoolServer.", fname),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn announce(
+    registry: &Option<Arc<dyn ClusterRegistry>>,
+    advertised_addr: Option<SocketAddr>,
+    addrs: &[SlackerAddr],
+    fnames: &[String],
+) -> Vec<Announcement> {
+    let registry = match *registry {
+        Some(ref r) => r,
+        None => return Vec::new(),
+    };
+    let namespaces = namespaces(fnames);
+    let announced = match advertised_addr {
+        Some(addr) => vec![addr],
+        None => addrs
+            .iter()
+            .filter_map(|addr| match *addr {
+                SlackerAddr::Tcp(addr) if addr.ip().is_unspecified() => {
+                    error!(
+                        "not announcing {}, set an advertised address to announce it",
+                        addr
+                    );
+                    None
+                }
+                SlackerAddr::Tcp(addr) => Some(addr),
+                SlackerAddr::Unix(_) => None,
+            })
+            .collect(),
+    };
+    announced
+        .into_iter()
+        .filter_map(|addr| {
+            Announcement::new(registry.clone(), addr, namespaces.clone())
+                .map_err(|e| error!("failed to announce {}: {}", addr, e))
+                .ok()
+        })
+        .collect()
+}
+
+// Keeps announced namespaces in line with functions added or removed at runtime.
+fn reannounce<R>(
+    changes: UnboundedReceiver<()>,
+    functions: FunctionRegistry<R>,
+    announcements: Rc<RefCell<Vec<Announcement>>>,
+) -> Box<dyn Future<Item = (), Error = ()>>
+where
+    R: 'static,
+{
+    Box::new(changes.for_each(move |()| {
+        let namespaces = namespaces(&functions.fnames());
+        for announcement in announcements.borrow_mut().iter_mut() {
+            if let Err(e) = announcement.update(namespaces.clone()) {
+                warn!("failed to update announcement: {}", e);
+            }
+        }
+        Ok(())
+    }))
+}
+
+struct ConnectionGuard {
+    connections: Rc<Cell<usize>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    conn: Arc<ConnectionInfo>,
+    hooks: Arc<Vec<Arc<dyn ConnectionHook>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.set(self.connections.get() - 1);
+        if let Some(ref metrics) = self.metrics {
+            metrics.connections(-1);
+        }
+        self.conn.notifier().close();
+        for hook in self.hooks.iter() {
+            hook.on_disconnect(&self.conn);
+        }
+    }
+}
+
+// The protocol drops the service along with its connection.
+struct Tracked<S> {
+    inner: S,
+    in_flight: Rc<Cell<usize>>,
+    _connection: ConnectionGuard,
+}
+
+impl<S> Service for Tracked<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let in_flight = self.in_flight.clone();
+        in_flight.set(in_flight.get() + 1);
+        Box::new(self.inner.call(req).then(move |r| {
+            in_flight.set(in_flight.get() - 1);
+            r
+        }))
+    }
+}
+
+// packets pushed to a connection wait for it to be written beyond this
+const PUSH_BUFFER: usize = 64;
+
+// Resolves once the server starts draining.
+type DrainSignal = Shared<oneshot::Receiver<()>>;
+
+// The Slacker protocol, except that connections stop reading requests once
+// the server drains. They then close after writing the pending responses.
+// Besides the responses, they write the packets the service pushes.
+struct DrainingSlacker {
+    drain: DrainSignal,
+    metrics: Option<Arc<dyn Metrics>>,
+    pushed: RefCell<Option<mpsc::Receiver<SlackerPacket>>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for DrainingSlacker {
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Transport = Pushing<Draining<Framed<T, SlackerCodec>>>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        let pushed = self
+            .pushed
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Transport already bound."))?;
+        Ok(Pushing {
+            inner: Draining {
+                inner: SlackerCodec::new(self.metrics.clone()).framed(io),
+                drain: self.drain.clone(),
+            },
+            pushed,
+            buffered: None,
+        })
+    }
+}
+
+struct Draining<T> {
+    inner: T,
+    drain: DrainSignal,
+}
+
+impl<T> Stream for Draining<T>
+where
+    T: Stream<Item = (RequestId, SlackerPacket), Error = io::Error>,
+{
+    type Item = (RequestId, SlackerPacket);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self.drain.poll() {
+            Ok(Async::NotReady) => self.inner.poll(),
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for Draining<T>
+where
+    T: Sink<SinkItem = (RequestId, SlackerPacket), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, SlackerPacket);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_complete()
+    }
+}
+
+// Writes pushed packets before the responses that follow them, so the
+// chunks of a stream precede the response ending it.
+struct Pushing<T> {
+    inner: T,
+    pushed: mpsc::Receiver<SlackerPacket>,
+    // a pushed packet waiting for room in `inner`
+    buffered: Option<SlackerPacket>,
+}
+
+impl<T> Pushing<T>
+where
+    T: Sink<SinkItem = (RequestId, SlackerPacket), SinkError = io::Error>,
+{
+    // Ready once there is nothing left to push for now.
+    fn start_pushed(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let packet = match self.buffered.take() {
+                Some(packet) => packet,
+                None => match self.pushed.poll() {
+                    Ok(Async::Ready(Some(packet))) => packet,
+                    _ => return Ok(Async::Ready(())),
+                },
+            };
+            let id = packet.0.serial_id as RequestId;
+            if let AsyncSink::NotReady((_, packet)) = self.inner.start_send((id, packet))? {
+                self.buffered = Some(packet);
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+impl<T: Stream> Stream for Pushing<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<T::Item>, T::Error> {
+        self.inner.poll()
+    }
+}
+
+impl<T> Sink for Pushing<T>
+where
+    T: Sink<SinkItem = (RequestId, SlackerPacket), SinkError = io::Error>,
+{
+    type SinkItem = (RequestId, SlackerPacket);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        if self.start_pushed()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let pushed = self.start_pushed()?;
+            if self.inner.poll_complete()?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            if pushed.is_ready() {
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+// Creates the service of a connection.
+type NewConnectionService = Box<dyn Fn(Arc<ConnectionInfo>) -> io::Result<BoxService>>;
+
+struct Acceptor {
+    new_service: NewConnectionService,
+    handle: Handle,
+    drain: DrainSignal,
+    draining: Cell<bool>,
+    in_flight: Rc<Cell<usize>>,
+    connections: Rc<Cell<usize>>,
+    handshakes: Rc<Cell<usize>>,
+    max_connections: Option<usize>,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    connection_limit: Option<Limit>,
+    function_limits: Rc<BTreeMap<String, Rc<RefCell<LimitState>>>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    session_factory: Option<SessionFactory>,
+    state: Option<AnyState>,
+    connection_hooks: Arc<Vec<Arc<dyn ConnectionHook>>>,
+}
+
+impl Acceptor {
+    fn new(
+        config: &ServerConfig,
+        new_service: NewConnectionService,
+        handle: &Handle,
+        drain: DrainSignal,
+    ) -> Acceptor {
+        Acceptor {
+            new_service,
+            handle: handle.clone(),
+            drain,
+            draining: Cell::new(false),
+            in_flight: Rc::new(Cell::new(0)),
+            connections: Rc::new(Cell::new(0)),
+            handshakes: Rc::new(Cell::new(0)),
+            max_connections: config.max_connections,
+            interceptors: Arc::new(config.interceptors.clone()),
+            authenticator: config.authenticator.clone(),
+            authorizer: config.authorizer.clone(),
+            connection_limit: config.connection_limit,
+            function_limits: Rc::new(
+                config
+                    .function_limits
+                    .iter()
+                    .map(|(fname, limit)| (fname.clone(), LimitState::new(*limit)))
+                    .collect(),
+            ),
+            metrics: config.metrics.clone(),
+            session_factory: config.session_factory.clone(),
+            state: config.state.clone(),
+            connection_hooks: Arc::new(config.connection_hooks.clone()),
+        }
+    }
+
+    // TLS handshakes in progress count as connections.
+    fn is_full(&self) -> bool {
+        matches!(
+            self.max_connections,
+            Some(max) if self.connections.get() + self.handshakes.get() >= max
+        )
+    }
+
+    fn accept<T>(&self, io: T, mut conn: ConnectionInfo) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        if self.draining.get() {
+            debug!("shutting down, closing {}", conn.peer_name());
+            return Ok(());
+        }
+        if self.is_full() {
+            warn!("too many connections, closing {}", conn.peer_name());
+            return Ok(());
+        }
+
+        debug!("accepted connection from {}", conn.peer_name());
+        if let Some(ref factory) = self.session_factory {
+            let session = factory(&conn);
+            conn.set_session(session);
+        }
+        if let Some(ref state) = self.state {
+            conn.set_state(state.clone());
+        }
+        let (push, pushed) = mpsc::channel(PUSH_BUFFER);
+        conn.set_push(push);
+        for hook in self.connection_hooks.iter() {
+            hook.on_connect(&conn);
+        }
+        let conn = Arc::new(conn);
+        self.connections.set(self.connections.get() + 1);
+        if let Some(ref metrics) = self.metrics {
+            metrics.connections(1);
+        }
+        // undoes the above if the service can't be created
+        let guard = ConnectionGuard {
+            connections: self.connections.clone(),
+            metrics: self.metrics.clone(),
+            conn: conn.clone(),
+            hooks: self.connection_hooks.clone(),
+        };
+        let mut service = (self.new_service)(conn.clone())?;
+        if !self.interceptors.is_empty() {
+            service = Box::new(Intercepted::new(
+                service,
+                conn.clone(),
+                self.interceptors.clone(),
+            ));
+        }
+        if let Some(ref authenticator) = self.authenticator {
+            service = Box::new(Authenticated::new(
+                service,
+                conn.clone(),
+                authenticator.clone(),
+                self.authorizer.clone(),
+            ));
+        }
+        if self.connection_limit.is_some() || !self.function_limits.is_empty() {
+            service = Box::new(Limited::new(
+                service,
+                self.connection_limit,
+                self.function_limits.clone(),
+            ));
+        }
+        if let Some(ref metrics) = self.metrics {
+            service = Box::new(Metered::new(service, metrics.clone()));
+        }
+        service = Box::new(Traced::new(service, conn.clone()));
+        service = Box::new(OneWay(service));
+        let service = Tracked {
+            inner: service,
+            in_flight: self.in_flight.clone(),
+            _connection: guard,
+        };
+        let proto = DrainingSlacker {
+            drain: self.drain.clone(),
+            metrics: self.metrics.clone(),
+            pushed: RefCell::new(Some(pushed)),
+        };
+        proto.bind_server(&self.handle, io, service);
+        Ok(())
+    }
+}
+
+fn incoming(
+    listener: Listener,
+    acceptor: Rc<Acceptor>,
+    config: &ServerConfig,
+) -> io::Result<Box<dyn Future<Item = (), Error = io::Error>>> {
+    let handle = acceptor.handle.clone();
+    match listener {
+        Listener::Tcp(listener) => {
+            let addr = listener.local_addr()?;
+            let nodelay = config.nodelay;
+            let keepalive = config.keepalive;
+            #[cfg(feature = "tls")]
+            let tls = config.tls.clone();
+            Ok(Box::new(
+                TcpListener::from_listener(listener, &addr, &handle)?
+                    .incoming()
+                    .for_each(move |(socket, peer)| {
+                        // a failing socket only loses its own connection
+                        if let Err(e) = socket
+                            .set_nodelay(nodelay)
+                            .and_then(|_| socket.set_keepalive(keepalive))
+                        {
+                            warn!("failed to set up connection from {}: {}", peer, e);
+                            return Ok(());
+                        }
+                        let conn = ConnectionInfo::new(Some(SlackerAddr::Tcp(peer)));
+                        #[cfg(feature = "tls")]
+                        {
+                            if let Some(ref tls) = tls {
+                                accept_tls(tls, socket, conn, acceptor.clone());
+                                return Ok(());
+                            }
+                        }
+                        if let Err(e) = acceptor.accept(socket, conn) {
+                            error!("failed to serve connection from {}: {}", peer, e);
+                        }
+                        Ok(())
+                    }),
+            ))
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            let addr = Listener::Unix(listener.try_clone()?).local_addr()?;
+            Ok(Box::new(
+                UnixListener::from_listener(listener, &handle)?
+                    .incoming()
+                    .for_each(move |(socket, peer)| {
+                        let peer = peer.as_pathname().map(|p| SlackerAddr::Unix(p.to_owned()));
+                        if let Err(e) = acceptor.accept(socket, ConnectionInfo::new(peer)) {
+                            error!("failed to serve connection on {}: {}", addr, e);
+                        }
+                        Ok(())
+                    }),
+            ))
+        }
+    }
+}
+
+// The handshake runs as its own task so a slow client can't stall accepting,
+// and gives up its slot once the handshake timeout fires.
+#[cfg(feature = "tls")]
+fn accept_tls(
+    tls: &TlsServerConfig,
+    socket: TcpStream,
+    mut conn: ConnectionInfo,
+    acceptor: Rc<Acceptor>,
+) {
+    if acceptor.is_full() {
+        warn!("too many connections, closing {}", conn.peer_name());
+        return;
+    }
+    let handle = acceptor.handle.clone();
+    let deadline = match Timeout::new(tls.handshake_timeout(), &handle) {
+        Ok(deadline) => deadline,
+        Err(e) => {
+            error!(
+                "failed to serve connection from {}: {}",
+                conn.peer_name(),
+                e
+            );
+            return;
+        }
+    };
+    let handshakes = acceptor.handshakes.clone();
+    handshakes.set(handshakes.get() + 1);
+    let handshake = tls.acceptor().accept(socket).select2(deadline);
+    handle.spawn(handshake.then(move |r| {
+        handshakes.set(handshakes.get() - 1);
+        match r {
+            Ok(Either::A((stream, _))) => {
+                conn.peer_certificates = stream
+                    .get_ref()
+                    .1
+                    .get_peer_certificates()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|cert| cert.0)
+                    .collect();
+                if let Err(e) = acceptor.accept(stream, conn) {
+                    error!("failed to serve connection: {}", e);
+                }
+            }
+            // dropping the handshake closes the connection
+            Ok(Either::B(_)) => warn!("tls handshake with {} timed out", conn.peer_name()),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => {
+                warn!("tls handshake with {} failed: {}", conn.peer_name(), e)
+            }
+        }
+        Ok(())
+    }));
+}
+
+fn serve_until<R, F>(
+    config: &ServerConfig,
+    new_service: NewConnectionService,
+    functions: &FunctionRegistry<R>,
+    shutdown: F,
+    drain_timeout: Duration,
+) -> io::Result<()>
+where
+    R: 'static,
+    F: Future<Item = (), Error = ()>,
+{
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let (start_drain, drain) = oneshot::channel();
+    let acceptor = Rc::new(Acceptor::new(config, new_service, &handle, drain.shared()));
+    let in_flight = acceptor.in_flight.clone();
+    let connections = acceptor.connections.clone();
+
+    let mut listeners = Vec::new();
+    for addr in &config.addrs {
+        listeners.push(Listener::bind(addr)?);
+    }
+    for listener in &config.listeners {
+        listeners.push(listener.try_clone()?);
+    }
+
+    let mut addrs = Vec::new();
+    let mut accepts = Vec::new();
+    let mut socket_files = SocketFiles(Vec::new());
+    for listener in listeners {
+        let addr = listener.local_addr()?;
+        info!("listening on {}", addr);
+        if let SlackerAddr::Unix(ref path) = addr {
+            socket_files.0.push(path.clone());
+        }
+        addrs.push(addr);
+        accepts.push(incoming(listener, acceptor.clone(), config)?);
+    }
+    // on its own so its failure doesn't stop serving calls
+    if let Some(ref metrics_addr) = config.metrics_addr {
+        let metrics = config.metrics.iter().cloned().collect();
+        handle.spawn(
+            metrics_endpoint(metrics_addr, metrics, &handle)?
+                .map_err(|e| error!("metrics endpoint stopped: {}", e)),
+        );
+    }
+    // before announcing, so no change is missed
+    let changes = functions.changes();
+    let announcements = Rc::new(RefCell::new(announce(
+        &config.registry,
+        config.advertised_addr,
+        &addrs,
+        &functions.fnames(),
+    )));
+    if !announcements.borrow().is_empty() {
+        handle.spawn(reannounce(
+            changes,
+            functions.clone(),
+            announcements.clone(),
+        ));
+    }
+
+    // dropping the accept futures closes the listeners
+    match core.run(join_all(accepts).select2(shutdown)) {
+        Err(Either::A((e, _))) => return Err(e),
+        _ => info!("shutting down {:?}", addrs),
+    }
+    announcements.borrow_mut().clear();
+
+    // connections close by themselves once their responses are written
+    acceptor.draining.set(true);
+    start_drain.send(()).ok();
+    let open = connections.clone();
+    let drained = Interval::new(Duration::from_millis(10), &handle)?
+        .take_while(move |_| Ok(open.get() > 0))
+        .for_each(|_| Ok(()));
+    let deadline = Timeout::new(drain_timeout, &handle)?;
+    match core.run(drained.select2(deadline)) {
+        Ok(Either::B(_)) => warn!(
+            "drain timeout, closing {} connections of {:?} with {} requests in flight",
+            connections.get(),
+            addrs,
+            in_flight.get()
+        ),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => return Err(e),
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::ClientManager;
+    use cluster::{Discovery, MemoryRegistry};
+    use serde_json;
+    use serde_json::value::Value as Json;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn announce_skips_unspecified_addresses() {
+        let registry = Arc::new(MemoryRegistry::new());
+        let fnames = vec!["demo/echo".to_owned()];
+        let addrs = vec![
+            SlackerAddr::Tcp("0.0.0.0:2104".parse().unwrap()),
+            SlackerAddr::Tcp("127.0.0.1:2105".parse().unwrap()),
+        ];
+        let registry_ref: Option<Arc<dyn ClusterRegistry>> = Some(registry.clone());
+
+        let announcements = announce(&registry_ref, None, &addrs, &fnames);
+        assert_eq!(
+            registry.lookup("demo").unwrap(),
+            vec!["127.0.0.1:2105".parse().unwrap()]
+        );
+        drop(announcements);
+
+        let advertised = "10.0.0.1:2104".parse().unwrap();
+        let _announcements = announce(&registry_ref, Some(advertised), &addrs, &fnames);
+        assert_eq!(registry.lookup("demo").unwrap(), vec![advertised]);
+    }
+
+    fn null() -> JsonRpcHandler {
+        RpcHandler::Async(Box::new(|_: &Vec<Json>| {
+            let (tx, rx) = ::futures::sync::oneshot::channel();
+            tx.send(Json::Null).unwrap();
+            rx
+        }))
+    }
+
+    // Waits for the server to announce `expected` for the namespace.
+    fn announced(registry: &MemoryRegistry, ns_name: &str, expected: Vec<SocketAddr>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while registry.lookup(ns_name).unwrap() != expected {
+            assert!(Instant::now() < deadline, "not announced in time");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn function_changes_reach_inspect_and_the_registry() {
+        let registry = Arc::new(MemoryRegistry::new());
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ServerBuilder::new()
+            .listener(listener)
+            .registry(registry.clone())
+            .build(BTreeMap::new())
+            .unwrap();
+        let functions = server.functions();
+        let (shutdown, stopped) = ::futures::sync::oneshot::channel::<()>();
+        let serving = thread::spawn(move || {
+            server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1))
+        });
+
+        let mut core = Core::new().unwrap();
+        let connect = ClientManager::new().connect(&mut core, &addr);
+        let client = core.run(connect).unwrap();
+        let serial_id = Cell::new(0);
+        let mut inspect = || -> Vec<String> {
+            serial_id.set(serial_id.get() + 1);
+            let header = SlackerPacketHeader {
+                version: PROTOCOL_VERSION_5,
+                serial_id: serial_id.get(),
+                packet_type: PACKET_TYPE_INSPECT_REQUEST,
+            };
+            let body = SlackerPacketBody::InspectRequest(SlackerInspectRequestPacket {
+                inspect_type: INSPECT_TYPE_FUNCTIONS,
+                data: Vec::new(),
+            });
+            match core.run(client.call(SlackerPacket(header, body))).unwrap() {
+                SlackerPacket(_, SlackerPacketBody::InspectResponse(r)) => {
+                    serde_json::from_slice(&r.data).unwrap()
+                }
+                packet => panic!("unexpected packet {:?}", packet),
+            }
+        };
+
+        functions.register("demo/a", null());
+        functions.register("demo/b", null());
+        functions.register("other/c", null());
+        assert_eq!(inspect(), vec!["demo/a", "demo/b", "other/c"]);
+        announced(&registry, "demo", vec![addr]);
+        announced(&registry, "other", vec![addr]);
+
+        assert!(functions.unregister("other/c"));
+        assert_eq!(inspect(), vec!["demo/a", "demo/b"]);
+        announced(&registry, "other", vec![]);
+        announced(&registry, "demo", vec![addr]);
+
+        assert_eq!(functions.unregister_namespace("demo"), 2);
+        assert!(inspect().is_empty());
+        announced(&registry, "demo", vec![]);
+
+        shutdown.send(()).unwrap();
+        serving.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn most_specific_pool_wins() {
+        let pool = |threads| JobQueue::new(threads, 1, QueuePolicy::Reject);
+        let server = ServerBuilder::new()
+            .build_mixed(BTreeMap::new(), 1)
+            .unwrap()
+            .with_pool("any", pool(1))
+            .with_pool("ns", pool(1))
+            .with_pool("fn", pool(1))
+            .assign_pool("*", "any")
+            .assign_pool("demo/*", "ns")
+            .assign_pool("demo/echo", "fn");
+        let patterns: Vec<String> = server
+            .resolve_pools()
+            .unwrap()
+            .into_iter()
+            .map(|(pattern, _)| pattern)
+            .collect();
+        assert_eq!(patterns, vec!["demo/echo", "demo/*", "*"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_service_releases_the_connection() {
+        let core = Core::new().unwrap();
+        let config = ServerBuilder::new().max_connections(1).config;
+        let (_start_drain, drain) = oneshot::channel();
+        let acceptor = Acceptor::new(
+            &config,
+            Box::new(|_| Err(io::Error::new(io::ErrorKind::Other, "no service"))),
+            &core.handle(),
+            drain.shared(),
+        );
+        let peer = SlackerAddr::Tcp("127.0.0.1:2104".parse().unwrap());
+        let (socket, _) = ::std::os::unix::net::UnixStream::pair().unwrap();
+        let socket = tuds::UnixStream::from_stream(socket, &core.handle()).unwrap();
+
+        assert!(acceptor
+            .accept(socket, ConnectionInfo::new(Some(peer)))
+            .is_err());
+        assert_eq!(acceptor.connections.get(), 0);
+    }
+
+    #[test]
+    fn server_rejects_blocking_functions() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let echo: JsonRpcHandler = RpcHandler::Blocking(Arc::new(|args| args[0].clone()));
+        let mut funcs = BTreeMap::new();
+        funcs.insert("demo/echo".to_owned(), echo);
+        let e = Server::new_mixed(addr, funcs).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,90 +1,341 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use futures::future::{err, ok};
-use futures::sync::oneshot::Receiver;
-use futures::{Future, IntoFuture};
-use futures_cpupool::CpuPool;
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot::{self, Receiver};
+use futures::{unsync, Async, AsyncSink, Future, IntoFuture, Poll, Sink, Stream};
 use tservice::Service;
 
 use serde::Serialize;
+use serde_json;
+use tracing::Span;
 
+use context::{FromContext, RequestContext};
+use interceptor::ConnectionInfo;
 use parser::*;
+use queue::JobQueue;
+use registry::{in_namespace, FunctionRegistry};
 use serializer::*;
+use trace::{with_context, TraceContext};
 
-pub type RpcFn<T> = Box<Fn(&Vec<T>) -> Receiver<T> + Send + Sync + 'static>;
-pub type RpcFnSync<T> = Arc<Fn(&Vec<T>) -> T + Send + Sync + 'static>;
+pub type RpcFn<T> = Box<dyn Fn(&Vec<T>) -> Receiver<T> + Send + Sync + 'static>;
+pub type RpcFnSync<T> = Arc<dyn Fn(&Vec<T>) -> T + Send + Sync + 'static>;
+pub type RpcFnWithContext<T> =
+    Box<dyn Fn(&RequestContext, &Vec<T>) -> Receiver<T> + Send + Sync + 'static>;
+pub type RpcFnSyncWithContext<T> =
+    Arc<dyn Fn(&RequestContext, &Vec<T>) -> io::Result<T> + Send + Sync + 'static>;
+pub type RpcStream<T> = Box<dyn Stream<Item = T, Error = io::Error>>;
+pub type RpcFnStream<T> = Box<dyn Fn(&Vec<T>) -> RpcStream<T> + Send + Sync + 'static>;
+
+/// A function that runs on the reactor, or one that blocks and runs on a
+/// thread pool of a `ThreadPoolServer`. The `WithContext` variants also
+/// receive the context of the request, and blocking ones may fail the call.
+/// A `Streaming` function sends its items in chunks to clients opening a
+/// stream, and all at once, as a list, to other clients.
+pub enum RpcHandler<T> {
+    Async(RpcFn<T>),
+    Blocking(RpcFnSync<T>),
+    AsyncWithContext(RpcFnWithContext<T>),
+    BlockingWithContext(RpcFnSyncWithContext<T>),
+    Streaming(RpcFnStream<T>),
+}
+
+impl<T: Send + 'static> RpcHandler<T> {
+    /// A handler taking `E`, e.g. `State<S>`, along with the arguments. The
+    /// call fails when `E` cannot be extracted from the request.
+    pub fn typed<E, F>(f: F) -> RpcHandler<T>
+    where
+        E: FromContext,
+        F: Fn(E, &Vec<T>) -> Receiver<T> + Send + Sync + 'static,
+    {
+        RpcHandler::AsyncWithContext(Box::new(move |ctx, args| {
+            match E::from_context(ctx) {
+                Some(e) => f(e, args),
+                None => {
+                    error!("nothing to extract for request {}", ctx.serial_id);
+                    // dropping the sender fails the call
+                    oneshot::channel().1
+                }
+            }
+        }))
+    }
+
+    /// Like `typed`, for blocking handlers.
+    pub fn typed_blocking<E, F>(f: F) -> RpcHandler<T>
+    where
+        E: FromContext,
+        F: Fn(E, &Vec<T>) -> T + Send + Sync + 'static,
+    {
+        RpcHandler::BlockingWithContext(Arc::new(move |ctx, args| match E::from_context(ctx) {
+            Some(e) => Ok(f(e, args)),
+            None => {
+                error!("nothing to extract for request {}", ctx.serial_id);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Nothing to extract from the request.",
+                ))
+            }
+        }))
+    }
+}
+
+pub type BoxService = Box<
+    dyn Service<
+        Request = SlackerPacket,
+        Response = SlackerPacket,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = SlackerPacket, Error = io::Error>>,
+    >,
+>;
+
+/// Boxes the future of a service so it can be used as a `BoxService`.
+pub struct Boxed<S>(pub S);
+
+impl<S> Service for Boxed<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        Box::new(self.0.call(req))
+    }
+}
+
+/// Keeps the response to a one-way call from being written.
+pub struct OneWay<S>(pub S);
+
+impl<S> Service for OneWay<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let one_way = match req {
+            SlackerPacket(_, SlackerPacketBody::Request(ref sreq)) => {
+                find_extension(&sreq.extensions, EXTENSION_ID_ONE_WAY).is_some()
+            }
+            _ => false,
+        };
+        if one_way {
+            Box::new(self.0.call(req).map(|SlackerPacket(mut header, body)| {
+                header.packet_type = PACKET_TYPE_NO_RESPONSE;
+                SlackerPacket(header, body)
+            }))
+        } else {
+            Box::new(self.0.call(req))
+        }
+    }
+}
+
+/// Whether `fname` matches `pattern`: `*`, a namespace like `rust.test/*`, or
+/// a full function name.
+pub fn matches_fname(pattern: &str, fname: &str) -> bool {
+    pattern == "*"
+        || pattern == fname
+        || (pattern.ends_with("/*")
+            && fname.find('/').map(|i| &fname[..i]) == Some(&pattern[..pattern.len() - 2]))
+}
+
+pub fn error_response(header: SlackerPacketHeader, result_code: u8) -> SlackerPacket {
+    let mut resp_header = header;
+    resp_header.packet_type = PACKET_TYPE_ERROR;
+    SlackerPacket(
+        resp_header,
+        SlackerPacketBody::Error(SlackerErrorPacket { result_code }),
+    )
+}
+
+fn inspect_response<F>(
+    header: SlackerPacketHeader,
+    req: &SlackerInspectRequestPacket,
+    functions: &FunctionRegistry<F>,
+) -> SlackerPacket {
+    let data = match req.inspect_type {
+        // optionally filtered by the namespace in the request
+        INSPECT_TYPE_FUNCTIONS => {
+            let ns_name = String::from_utf8_lossy(&req.data);
+            let fnames: Vec<String> = functions
+                .fnames()
+                .into_iter()
+                .filter(|fname| ns_name.is_empty() || in_namespace(fname, &ns_name))
+                .collect();
+            serde_json::to_vec(&fnames).unwrap_or_default()
+        }
+        // of the function named in the request
+        INSPECT_TYPE_META => {
+            let fname = String::from_utf8_lossy(&req.data);
+            if functions.get(&fname).is_some() {
+                let mut meta = serde_json::Map::new();
+                meta.insert(
+                    "idempotent".to_owned(),
+                    functions.is_idempotent(&fname).into(),
+                );
+                meta.insert("name".to_owned(), fname.as_ref().into());
+                serde_json::to_vec(&meta).unwrap_or_default()
+            } else {
+                b"null".to_vec()
+            }
+        }
+        _ => b"null".to_vec(),
+    };
+    let mut resp_header = header;
+    resp_header.packet_type = PACKET_TYPE_INSPECT_RESPONSE;
+    SlackerPacket(
+        resp_header,
+        SlackerPacketBody::InspectResponse(SlackerInspectResponsePacket { data }),
+    )
+}
 
 pub struct SlackerService<T>
 where
     T: Serialize + Send + Sync + 'static,
 {
-    functions: Arc<BTreeMap<String, RpcFn<T>>>,
-    serializer: Arc<Serializer<Format = T>>,
+    functions: FunctionRegistry<RpcHandler<T>>,
+    serializer: Arc<dyn Serializer<Format = T>>,
+    queue: Option<JobQueue>,
+    pools: Arc<Vec<(String, JobQueue)>>,
+    conn: Arc<ConnectionInfo>,
+    streams: Streams,
 }
 
+// Open streams of a connection by id, cancelled by dropping their sender.
+type Streams = Rc<RefCell<HashMap<i32, unsync::oneshot::Sender<()>>>>;
+
+// More streams opened on a connection are rejected with
+// `RESULT_CODE_SERVER_BUSY`. Open streams count toward the 32 requests
+// tokio-proto serves at a time per connection, which must leave room for
+// others, e.g. those cancelling streams.
+const MAX_STREAMS: usize = 16;
+
 impl<T> SlackerService<T>
 where
-    T: Serialize + Send + Sync,
+    T: Serialize + Send + Sync + 'static,
 {
     pub fn new(
-        functions: Arc<BTreeMap<String, RpcFn<T>>>,
-        serializer: Arc<Serializer<Format = T>>,
+        functions: FunctionRegistry<RpcHandler<T>>,
+        serializer: Arc<dyn Serializer<Format = T>>,
+        conn: Arc<ConnectionInfo>,
     ) -> SlackerService<T> {
         SlackerService {
             functions,
             serializer,
+            queue: None,
+            pools: Arc::new(Vec::new()),
+            conn,
+            streams: Rc::new(RefCell::new(HashMap::new())),
         }
     }
+
+    /// Runs blocking handlers on `queue`, or the first of `pools` whose
+    /// pattern matches the function, rather than on the reactor.
+    pub fn with_queues(mut self, queue: JobQueue, pools: Arc<Vec<(String, JobQueue)>>) -> Self {
+        self.queue = Some(queue);
+        self.pools = pools;
+        self
+    }
 }
 
 impl<T> Service for SlackerService<T>
 where
-    T: Serialize + Send + Sync,
+    T: Serialize + Send + Sync + From<Vec<T>> + 'static,
 {
     type Request = SlackerPacket;
     type Response = SlackerPacket;
     type Error = io::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let SlackerPacket(header, body) = req;
         match body {
             SlackerPacketBody::Request(sreq) => {
                 debug!("getting request: {:?}", sreq.fname);
-                if let Some(f) = self.functions.get(&sreq.fname) {
-                    let s = self.serializer.clone();
-                    match s.deserialize_vec(&sreq.arguments) {
-                        Ok(args) => Box::new(
-                            f(&args)
-                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-                                .and_then(move |r| s.serialize(&r).into_future())
-                                .and_then(move |result| {
-                                    let mut resp_header = header.clone();
-                                    resp_header.packet_type = PACKET_TYPE_RESPONSE;
-                                    debug!("sending results");
-                                    let body = SlackerPacketBody::Response(SlackerResponsePacket {
-                                        result_code: RESULT_CODE_SUCCESS,
-                                        content_type: sreq.content_type,
-                                        data: result,
-                                    });
-                                    ok(SlackerPacket(resp_header, body))
-                                }),
-                        ),
-                        Err(e) => Box::new(err(e)),
+                if let Some((STREAM_CANCEL, id)) = stream_op(&sreq.extensions) {
+                    // of a stream or a subscription to notifications
+                    self.streams.borrow_mut().remove(&id);
+                    self.conn.notifier().unsubscribe(id);
+                    return Box::new(
+                        self.serializer
+                            .serialize(&T::from(Vec::new()))
+                            .map(|data| {
+                                response(
+                                    header,
+                                    sreq.content_type,
+                                    data,
+                                    vec![stream_extension(STREAM_END, id)],
+                                )
+                            })
+                            .into_future(),
+                    );
+                }
+                if find_extension(&sreq.extensions, EXTENSION_ID_NOTIFICATIONS).is_some() {
+                    return Box::new(ok(self.conn.notifier().subscribe(header)));
+                }
+                let queue = self
+                    .pools
+                    .iter()
+                    .find(|&(pattern, _)| matches_fname(pattern, &sreq.fname))
+                    .map(|(_, queue)| queue)
+                    .or(self.queue.as_ref());
+                let s = self.serializer.clone();
+                match self.functions.get(&sreq.fname).as_deref() {
+                    Some(RpcHandler::Async(f)) => call_async(|args| f(args), s, header, sreq),
+                    Some(RpcHandler::Blocking(f)) => {
+                        let f = f.clone();
+                        call_blocking(move |args| Ok(f(args)), s, queue, header, sreq)
+                    }
+                    Some(RpcHandler::AsyncWithContext(f)) => {
+                        let ctx = RequestContext::new(&self.conn, &header, &sreq);
+                        let guard = ctx.cancel_guard();
+                        Box::new(
+                            call_async(|args| f(&ctx, args), s, header, sreq).then(move |r| {
+                                drop(guard);
+                                r
+                            }),
+                        )
+                    }
+                    Some(RpcHandler::BlockingWithContext(f)) => {
+                        let ctx = RequestContext::new(&self.conn, &header, &sreq);
+                        let guard = ctx.cancel_guard();
+                        let f = f.clone();
+                        Box::new(
+                            call_blocking(move |args| f(&ctx, args), s, queue, header, sreq).then(
+                                move |r| {
+                                    drop(guard);
+                                    r
+                                },
+                            ),
+                        )
                     }
-                } else {
-                    Box::new(ok(SlackerPacket(
+                    Some(RpcHandler::Streaming(f)) => call_stream(
+                        |args| f(args),
+                        s,
+                        self.conn.push(),
+                        self.streams.clone(),
                         header,
-                        SlackerPacketBody::Error(SlackerErrorPacket {
-                            result_code: RESULT_CODE_NOT_FOUND,
-                        }),
-                    )))
+                        sreq,
+                    ),
+                    None => Box::new(ok(error_response(header, RESULT_CODE_NOT_FOUND))),
                 }
             }
+            SlackerPacketBody::InspectRequest(ref ireq) => {
+                Box::new(ok(inspect_response(header, ireq, &self.functions)))
+            }
             SlackerPacketBody::Ping => {
-                let mut resp_header = header.clone();
+                let mut resp_header = header;
                 resp_header.packet_type = PACKET_TYPE_PONG;
                 Box::new(ok(SlackerPacket(resp_header, SlackerPacketBody::Pong)))
             }
@@ -96,91 +347,321 @@ where
     }
 }
 
-pub struct SlackerServiceSync<T>
+fn call_async<T, F>(
+    f: F,
+    s: Arc<dyn Serializer<Format = T>>,
+    header: SlackerPacketHeader,
+    sreq: SlackerRequestPacket,
+) -> Box<dyn Future<Item = SlackerPacket, Error = io::Error>>
 where
     T: Serialize + Send + Sync + 'static,
+    F: FnOnce(&Vec<T>) -> Receiver<T>,
 {
-    functions: Arc<BTreeMap<String, RpcFnSync<T>>>,
-    serializer: Arc<Serializer<Format = T>>,
-    threads: usize,
-    pool: CpuPool,
+    match s.deserialize_vec(&sreq.arguments) {
+        Ok(args) => Box::new(
+            f(&args)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .and_then(move |r| s.serialize(&r).into_future())
+                .and_then(move |result| {
+                    let mut resp_header = header;
+                    resp_header.packet_type = PACKET_TYPE_RESPONSE;
+                    debug!("sending results");
+                    let body = SlackerPacketBody::Response(SlackerResponsePacket {
+                        result_code: RESULT_CODE_SUCCESS,
+                        content_type: sreq.content_type,
+                        data: result,
+                        extensions: Vec::new(),
+                    });
+                    ok(SlackerPacket(resp_header, body))
+                }),
+        ),
+        Err(e) => Box::new(err(e)),
+    }
 }
 
-impl<T> SlackerServiceSync<T>
+fn call_blocking<T, F>(
+    f: F,
+    s: Arc<dyn Serializer<Format = T>>,
+    queue: Option<&JobQueue>,
+    header: SlackerPacketHeader,
+    sreq: SlackerRequestPacket,
+) -> Box<dyn Future<Item = SlackerPacket, Error = io::Error>>
 where
     T: Serialize + Send + Sync + 'static,
+    F: FnOnce(&Vec<T>) -> io::Result<T> + Send + 'static,
 {
-    pub fn new(
-        functions: Arc<BTreeMap<String, RpcFnSync<T>>>,
-        serializer: Arc<Serializer<Format = T>>,
-        threads: usize,
-    ) -> SlackerServiceSync<T> {
-        let pool = CpuPool::new(threads);
-        SlackerServiceSync {
-            functions,
-            serializer,
-            threads,
-            pool,
+    match queue {
+        Some(queue) => {
+            // the job runs on another thread, take the request's trace along
+            let context = TraceContext::current();
+            let span = Span::current();
+            queue.spawn(header, move || {
+                let _enter = span.enter();
+                with_context(context, || call_sync(f, &s, header, sreq))
+            })
+        }
+        None => {
+            error!("no thread pool to run {}", sreq.fname);
+            Box::new(err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No thread pool to run a blocking function.",
+            )))
         }
     }
 }
 
-impl<T> Service for SlackerServiceSync<T>
+fn call_sync<T, F>(
+    f: F,
+    s: &Arc<dyn Serializer<Format = T>>,
+    header: SlackerPacketHeader,
+    sreq: SlackerRequestPacket,
+) -> io::Result<SlackerPacket>
 where
     T: Serialize + Send + Sync + 'static,
+    F: FnOnce(&Vec<T>) -> io::Result<T>,
 {
-    type Request = SlackerPacket;
-    type Response = SlackerPacket;
-    type Error = io::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    s.deserialize_vec(&sreq.arguments)
+        .and_then(|v| f(&v))
+        .and_then(|v| s.serialize(&v))
+        .map(move |result| {
+            debug!("getting results");
+            let mut resp_header = header;
+            resp_header.packet_type = PACKET_TYPE_RESPONSE;
+            let body = SlackerPacketBody::Response(SlackerResponsePacket {
+                result_code: RESULT_CODE_SUCCESS,
+                content_type: sreq.content_type,
+                data: result,
+                extensions: Vec::new(),
+            });
+            SlackerPacket(resp_header, body)
+        })
+}
 
-    fn call(&self, req: Self::Request) -> Self::Future {
-        let SlackerPacket(header, body) = req;
-        match body {
-            SlackerPacketBody::Request(sreq) => {
-                debug!("getting request: {:?}", sreq.fname);
-                if let Some(fi) = self.functions.get(&sreq.fname) {
-                    let f = fi.clone();
-                    let s = self.serializer.clone();
-
-                    Box::new(
-                        self.pool
-                            .spawn_fn(move || -> Result<Self::Response, Self::Error> {
-                                s.deserialize_vec(&sreq.arguments)
-                                    .map(|v| f(&v))
-                                    .and_then(|v| s.serialize(&v))
-                                    .map(move |result| {
-                                        debug!("getting results");
-                                        let mut resp_header = header.clone();
-                                        resp_header.packet_type = PACKET_TYPE_RESPONSE;
-                                        let body =
-                                            SlackerPacketBody::Response(SlackerResponsePacket {
-                                                result_code: RESULT_CODE_SUCCESS,
-                                                content_type: sreq.content_type,
-                                                data: result,
-                                            });
-                                        SlackerPacket(resp_header, body)
-                                    })
-                            }),
-                    )
-                } else {
-                    Box::new(ok(SlackerPacket(
+fn response(
+    header: SlackerPacketHeader,
+    content_type: u8,
+    data: Vec<u8>,
+    extensions: Vec<SlackerExtension>,
+) -> SlackerPacket {
+    let mut resp_header = header;
+    resp_header.packet_type = PACKET_TYPE_RESPONSE;
+    let body = SlackerPacketBody::Response(SlackerResponsePacket {
+        result_code: RESULT_CODE_SUCCESS,
+        content_type,
+        data,
+        extensions,
+    });
+    SlackerPacket(resp_header, body)
+}
+
+fn call_stream<T, F>(
+    f: F,
+    s: Arc<dyn Serializer<Format = T>>,
+    push: Option<&Sender<SlackerPacket>>,
+    streams: Streams,
+    header: SlackerPacketHeader,
+    sreq: SlackerRequestPacket,
+) -> Box<dyn Future<Item = SlackerPacket, Error = io::Error>>
+where
+    T: Serialize + Send + Sync + From<Vec<T>> + 'static,
+    F: FnOnce(&Vec<T>) -> RpcStream<T>,
+{
+    let content_type = sreq.content_type;
+    let push = match (stream_op(&sreq.extensions), push) {
+        (Some((STREAM_OPEN, _)), Some(push)) => Some(push.clone()),
+        _ => None,
+    };
+    if push.is_some() && streams.borrow().len() >= MAX_STREAMS {
+        warn!("too many open streams, rejecting {}", sreq.fname);
+        return Box::new(ok(error_response(header, RESULT_CODE_SERVER_BUSY)));
+    }
+    let stream = match s.deserialize_vec(&sreq.arguments) {
+        Ok(args) => f(&args),
+        Err(e) => return Box::new(err(e)),
+    };
+    match push {
+        Some(push) => {
+            let id = header.serial_id;
+            let (cancel, cancelled) = unsync::oneshot::channel();
+            streams.borrow_mut().insert(id, cancel);
+            let end_s = s.clone();
+            Box::new(
+                PushChunks {
+                    stream,
+                    cancelled,
+                    push,
+                    s,
+                    header,
+                    content_type,
+                    items: Vec::new(),
+                    chunk: None,
+                }
+                .then(move |r| {
+                    streams.borrow_mut().remove(&id);
+                    let data = end_s.serialize(&T::from(r?))?;
+                    Ok(response(
                         header,
-                        SlackerPacketBody::Error(SlackerErrorPacket {
-                            result_code: RESULT_CODE_NOT_FOUND,
-                        }),
-                    )))
+                        content_type,
+                        data,
+                        vec![stream_extension(STREAM_END, id)],
+                    ))
+                }),
+            )
+        }
+        // clients not opening a stream get all items at once
+        None => Box::new(stream.collect().and_then(move |items| {
+            s.serialize(&T::from(items))
+                .map(|data| response(header, content_type, data, Vec::new()))
+        })),
+    }
+}
+
+const MAX_CHUNK_ITEMS: usize = 256;
+
+// Pushes the items of a stream in chunks as they are ready, and resolves to
+// the last ones, which go with the response, once the stream ends or is
+// cancelled. Waits for room in `push` before taking more items.
+struct PushChunks<T> {
+    stream: RpcStream<T>,
+    cancelled: unsync::oneshot::Receiver<()>,
+    push: Sender<SlackerPacket>,
+    s: Arc<dyn Serializer<Format = T>>,
+    header: SlackerPacketHeader,
+    content_type: u8,
+    items: Vec<T>,
+    chunk: Option<SlackerPacket>,
+}
+
+impl<T> PushChunks<T>
+where
+    T: Serialize + Send + Sync + From<Vec<T>> + 'static,
+{
+    fn take_chunk(&mut self) -> io::Result<()> {
+        let items = std::mem::take(&mut self.items);
+        let data = self.s.serialize(&T::from(items))?;
+        let id = self.header.serial_id;
+        self.chunk = Some(response(
+            self.header,
+            self.content_type,
+            data,
+            vec![stream_extension(STREAM_CHUNK, id)],
+        ));
+        Ok(())
+    }
+}
+
+impl<T> Future for PushChunks<T>
+where
+    T: Serialize + Send + Sync + From<Vec<T>> + 'static,
+{
+    type Item = Vec<T>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Vec<T>, io::Error> {
+        if self.cancelled.poll() != Ok(Async::NotReady) {
+            debug!("stream {} cancelled", self.header.serial_id);
+            return Ok(Async::Ready(Vec::new()));
+        }
+        loop {
+            if let Some(chunk) = self.chunk.take() {
+                match self.push.start_send(chunk) {
+                    Ok(AsyncSink::Ready) => {}
+                    Ok(AsyncSink::NotReady(chunk)) => {
+                        self.chunk = Some(chunk);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            "Connection closed.",
+                        ))
+                    }
                 }
             }
-            SlackerPacketBody::Ping => {
-                let mut resp_header = header.clone();
-                resp_header.packet_type = PACKET_TYPE_PONG;
-                Box::new(ok(SlackerPacket(header, SlackerPacketBody::Pong)))
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => {
+                    self.items.push(item);
+                    if self.items.len() >= MAX_CHUNK_ITEMS {
+                        self.take_chunk()?;
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready(std::mem::take(&mut self.items))),
+                Async::NotReady if self.items.is_empty() => return Ok(Async::NotReady),
+                Async::NotReady => self.take_chunk()?,
             }
-            _ => Box::new(err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Unsupported packet",
-            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::{AnyState, State};
+    use queue::QueuePolicy;
+    use serde_json::value::Value as Json;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    fn request(fname: &str) -> (SlackerPacketHeader, SlackerRequestPacket) {
+        let header = SlackerPacketHeader {
+            version: PROTOCOL_VERSION_6,
+            serial_id: 1,
+            packet_type: PACKET_TYPE_REQUEST,
+        };
+        let sreq = SlackerRequestPacket {
+            content_type: JSON_CONTENT_TYPE,
+            fname: fname.to_owned(),
+            arguments: b"[]".to_vec(),
+            extensions: Vec::new(),
+        };
+        (header, sreq)
+    }
+
+    #[test]
+    fn blocking_jobs_keep_the_trace_context() {
+        let queue = JobQueue::new(1, 1, QueuePolicy::Reject);
+        let serializer: Arc<dyn Serializer<Format = Json>> = Arc::new(JsonSerializer);
+        let (header, sreq) = request("demo/trace");
+        let seen = Arc::new(Mutex::new(None));
+        let seen_in_job = seen.clone();
+        let f = move |_: &Vec<Json>| {
+            *seen_in_job.lock().unwrap() = TraceContext::current();
+            Ok(Json::Null)
+        };
+
+        let context = TraceContext::new_root();
+        with_context(Some(context), || {
+            call_blocking(f, serializer, Some(&queue), header, sreq)
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(*seen.lock().unwrap(), Some(context));
+    }
+
+    #[test]
+    fn typed_blocking_fails_without_state() {
+        let handler =
+            RpcHandler::typed_blocking(|state: State<u32>, _: &Vec<Json>| Json::from(*state));
+        let mut funcs = BTreeMap::new();
+        funcs.insert("demo/state".to_owned(), handler);
+        let functions = FunctionRegistry::from(funcs);
+        let serve = |conn: ConnectionInfo| {
+            let (header, sreq) = request("demo/state");
+            SlackerService::new(functions.clone(), Arc::new(JsonSerializer), Arc::new(conn))
+                .with_queues(
+                    JobQueue::new(1, 1, QueuePolicy::Reject),
+                    Arc::new(Vec::new()),
+                )
+                .call(SlackerPacket(header, SlackerPacketBody::Request(sreq)))
+                .wait()
+        };
+
+        assert!(serve(ConnectionInfo::new(None)).is_err());
+        let mut conn = ConnectionInfo::new(None);
+        conn.set_state(AnyState::new(5u32));
+        match serve(conn).unwrap().1 {
+            SlackerPacketBody::Response(resp) => assert_eq!(resp.data, b"5".to_vec()),
+            body => panic!("unexpected response {:?}", body),
         }
     }
 }
@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    certs(&mut reader).map_err(|_| invalid_data("Invalid certificate file."))
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys =
+        pkcs8_private_keys(&mut reader).map_err(|_| invalid_data("Invalid private key file."))?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys =
+            rsa_private_keys(&mut reader).map_err(|_| invalid_data("Invalid private key file."))?;
+    }
+    keys.pop()
+        .ok_or_else(|| invalid_data("No private key found."))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let mut reader = BufReader::new(File::open(path)?);
+    match roots.add_pem_file(&mut reader) {
+        Ok((valid, _)) if valid > 0 => Ok(roots),
+        _ => Err(invalid_data("No valid CA certificate found.")),
+    }
+}
+
+// connections that haven't finished their handshake by then are closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+}
+
+impl TlsServerConfig {
+    pub fn new(cert_chain: &Path, key: &Path) -> io::Result<TlsServerConfig> {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(load_certs(cert_chain)?, load_key(key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsServerConfig::from_rustls(Arc::new(config)))
+    }
+
+    /// Mutual TLS: clients must present a certificate signed by `client_ca`.
+    pub fn with_client_auth(
+        cert_chain: &Path,
+        key: &Path,
+        client_ca: &Path,
+    ) -> io::Result<TlsServerConfig> {
+        let verifier = AllowAnyAuthenticatedClient::new(load_roots(client_ca)?);
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(load_certs(cert_chain)?, load_key(key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsServerConfig::from_rustls(Arc::new(config)))
+    }
+
+    pub fn from_rustls(config: Arc<ServerConfig>) -> TlsServerConfig {
+        TlsServerConfig {
+            acceptor: TlsAcceptor::from(config),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Closes connections that haven't finished the handshake in time, which
+    /// is 10 seconds by default.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> TlsServerConfig {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    connector: TlsConnector,
+}
+
+impl TlsClientConfig {
+    /// Only servers with a certificate signed by `ca` are trusted.
+    pub fn new(ca: &Path) -> io::Result<TlsClientConfig> {
+        let mut config = ClientConfig::new();
+        config.root_store = load_roots(ca)?;
+        Ok(TlsClientConfig::from_rustls(Arc::new(config)))
+    }
+
+    pub fn with_client_cert(
+        ca: &Path,
+        cert_chain: &Path,
+        key: &Path,
+    ) -> io::Result<TlsClientConfig> {
+        let mut config = ClientConfig::new();
+        config.root_store = load_roots(ca)?;
+        config.set_single_client_cert(load_certs(cert_chain)?, load_key(key)?);
+        Ok(TlsClientConfig::from_rustls(Arc::new(config)))
+    }
+
+    pub fn from_rustls(config: Arc<ClientConfig>) -> TlsClientConfig {
+        TlsClientConfig {
+            connector: TlsConnector::from(config),
+        }
+    }
+
+    pub fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+}
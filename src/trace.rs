@@ -0,0 +1,202 @@
+use std::cell::Cell;
+use std::io;
+use std::str;
+use std::sync::Arc;
+
+use futures::{Future, Poll};
+use rand::{thread_rng, Rng};
+use tracing::field::Empty;
+use tracing::Span;
+use tservice::Service;
+
+use interceptor::ConnectionInfo;
+use metrics::result_code;
+use parser::*;
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceContext>> = const { Cell::new(None) };
+}
+
+/// Identifies a span across services. Carried in the v6 trace extension as
+/// a W3C `traceparent` string so Clojure services can join the trace.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_id: Option<u64>,
+}
+
+fn random_u64() -> u64 {
+    thread_rng().gen()
+}
+
+impl TraceContext {
+    pub fn new_root() -> TraceContext {
+        TraceContext {
+            trace_id: (u128::from(random_u64()) << 64) | u128::from(random_u64()),
+            span_id: random_u64(),
+            parent_id: None,
+        }
+    }
+
+    pub fn child(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: random_u64(),
+            parent_id: Some(self.span_id),
+        }
+    }
+
+    /// The context of the server request being polled on this thread, if any.
+    pub fn current() -> Option<TraceContext> {
+        CURRENT.with(|c| c.get())
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+
+    /// The remote span becomes the parent of a new local one.
+    pub fn from_traceparent(traceparent: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        if parts.len() != 4 || parts[1].len() != 32 || parts[2].len() != 16 {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(parts[1], 16).ok()?;
+        let parent_id = u64::from_str_radix(parts[2], 16).ok()?;
+        Some(
+            TraceContext {
+                trace_id,
+                span_id: parent_id,
+                parent_id: None,
+            }
+            .child(),
+        )
+    }
+
+    pub fn extension(&self) -> SlackerExtension {
+        SlackerExtension {
+            ext_id: EXTENSION_ID_TRACE_CONTEXT,
+            data: self.to_traceparent().into_bytes(),
+        }
+    }
+
+    pub fn from_extensions(extensions: &[SlackerExtension]) -> Option<TraceContext> {
+        find_extension(extensions, EXTENSION_ID_TRACE_CONTEXT)
+            .and_then(|data| str::from_utf8(data).ok())
+            .and_then(TraceContext::from_traceparent)
+    }
+
+    /// Fills the `trace_id`, `span_id` and `parent_id` fields of `span`.
+    pub fn record(&self, span: &Span) {
+        span.record("trace_id", format!("{:032x}", self.trace_id).as_str());
+        span.record("span_id", format!("{:016x}", self.span_id).as_str());
+        if let Some(parent_id) = self.parent_id {
+            span.record("parent_id", format!("{:016x}", parent_id).as_str());
+        }
+    }
+}
+
+/// Runs `f` with `context` as the current trace context, e.g. on the thread
+/// a blocking job moved to.
+pub fn with_context<F, R>(context: Option<TraceContext>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let previous = CURRENT.with(|c| c.replace(context));
+    let r = f();
+    CURRENT.with(|c| c.set(previous));
+    r
+}
+
+/// Polls a future inside its span, with its trace context as the current one.
+pub struct Instrumented<F> {
+    inner: F,
+    span: Span,
+    context: TraceContext,
+}
+
+impl<F> Instrumented<F> {
+    pub fn new(inner: F, span: Span, context: TraceContext) -> Instrumented<F> {
+        Instrumented {
+            inner,
+            span,
+            context,
+        }
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let _enter = self.span.enter();
+        let inner = &mut self.inner;
+        with_context(Some(self.context), || inner.poll())
+    }
+}
+
+/// Wraps every request in a span joined to the caller's trace, if it sent
+/// one.
+pub struct Traced<S> {
+    inner: S,
+    conn: Arc<ConnectionInfo>,
+}
+
+impl<S> Traced<S> {
+    pub fn new(inner: S, conn: Arc<ConnectionInfo>) -> Traced<S> {
+        Traced { inner, conn }
+    }
+}
+
+impl<S> Service for Traced<S>
+where
+    S: Service<Request = SlackerPacket, Response = SlackerPacket, Error = io::Error>,
+    S::Future: 'static,
+{
+    type Request = SlackerPacket;
+    type Response = SlackerPacket;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (span, context) = match req {
+            SlackerPacket(ref header, SlackerPacketBody::Request(ref sreq)) => (
+                tracing::info_span!(
+                    "slacker.request",
+                    fname = sreq.fname.as_str(),
+                    serial_id = header.serial_id,
+                    content_type = sreq.content_type,
+                    peer = %self.conn.peer_name(),
+                    trace_id = Empty,
+                    span_id = Empty,
+                    parent_id = Empty,
+                    result_code = Empty
+                ),
+                TraceContext::from_extensions(&sreq.extensions)
+                    .unwrap_or_else(TraceContext::new_root),
+            ),
+            _ => return Box::new(self.inner.call(req)),
+        };
+
+        context.record(&span);
+
+        // handlers may start their work, and their own calls, right away
+        let inner = {
+            let _enter = span.enter();
+            with_context(Some(context), || self.inner.call(req))
+        };
+        let record_span = span.clone();
+        Box::new(Instrumented::new(
+            inner.map(move |resp| {
+                if let Some(code) = result_code(&resp) {
+                    record_span.record("result_code", code);
+                }
+                resp
+            }),
+            span,
+            context,
+        ))
+    }
+}
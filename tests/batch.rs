@@ -0,0 +1,76 @@
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{ClientManager, JsonRpcHandler, RpcHandler, ServerBuilder};
+
+#[test]
+fn calls_left_waiting_time_out_on_their_own() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let echo: JsonRpcHandler = RpcHandler::Async(Box::new(|args: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(args[0].clone()).unwrap();
+        rx
+    }));
+    // answered once the next call comes in
+    let held = Mutex::new(None::<oneshot::Sender<Json>>);
+    let slow: JsonRpcHandler = RpcHandler::Async(Box::new(move |_: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        if let Some(previous) = held.lock().unwrap().replace(tx) {
+            let _ = previous.send(Json::Null);
+        }
+        rx
+    }));
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build_handlers(
+            vec![
+                ("test/echo".to_owned(), echo),
+                ("test/slow".to_owned(), slow),
+            ]
+            .into_iter()
+            .collect(),
+        )
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+    let batch = client
+        .batch()
+        .call("test", "echo", vec![Json::from(1)])
+        .call("test", "slow", vec![])
+        .with_timeout(Duration::from_millis(200))
+        .send();
+    let results = core.run(batch).unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &Json::from(1));
+    assert_eq!(
+        results[1].as_ref().err().unwrap().kind(),
+        io::ErrorKind::TimedOut
+    );
+
+    // the late response of the timed out call is dropped
+    let slow = client.rpc_call("test", "slow", vec![]);
+    let echo = client.rpc_call("test", "echo", vec![Json::from(2)]);
+    assert_eq!(core.run(echo).unwrap(), Json::from(2));
+    drop(slow);
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
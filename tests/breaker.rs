@@ -0,0 +1,64 @@
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{
+    CircuitBreaker, ClientManager, ClusterClient, JsonRpcHandler, LoadBalance, RpcHandler,
+    ServerBuilder, SlackerAddr, StaticDiscovery,
+};
+
+#[test]
+fn cluster_calls_skip_servers_with_an_open_circuit() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let up = listener.local_addr().unwrap();
+    // nothing listens there
+    let down = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let echo: JsonRpcHandler = RpcHandler::Async(Box::new(|args: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(args[0].clone()).unwrap();
+        rx
+    }));
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build_handlers(vec![("test/echo".to_owned(), echo)].into_iter().collect())
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1)));
+
+    let breaker = Arc::new(CircuitBreaker::new(1.0, Duration::from_secs(60)).with_window(1, 1));
+    let permit = breaker
+        .acquire(&SlackerAddr::Tcp(down), "test/echo")
+        .unwrap();
+    breaker.record(&SlackerAddr::Tcp(down), "test/echo", permit, true);
+
+    let mut core = Core::new().unwrap();
+    let manager = ClientManager::new().with_circuit_breaker(breaker);
+    let cluster = ClusterClient::new(
+        manager,
+        core.handle(),
+        Arc::new(StaticDiscovery::new(vec![down, up])),
+        LoadBalance::RoundRobin,
+    );
+    for i in 0..4 {
+        let call = cluster.rpc_call("test", "echo", vec![Json::from(i)]);
+        assert_eq!(core.run(call).unwrap(), Json::from(i));
+    }
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
@@ -0,0 +1,107 @@
+#[macro_use]
+extern crate maplit;
+
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{
+    ClientManager, ConnectionHook, ConnectionInfo, JsonRpcHandler, RequestContext, RpcHandler,
+    ServerBuilder,
+};
+
+#[test]
+fn server_passes_the_request_context() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer: JsonRpcHandler =
+        RpcHandler::AsyncWithContext(Box::new(|ctx: &RequestContext, _: &Vec<Json>| {
+            let (tx, rx) = oneshot::channel();
+            tx.send(Json::from(ctx.peer_addr.as_ref().map(|a| a.to_string())))
+                .unwrap();
+            rx
+        }));
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build_handlers(btreemap! { "test/peer".to_owned() => peer })
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Default::default()));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+    let result = core.run(client.rpc_call("test", "peer", vec![])).unwrap();
+    assert!(result.as_str().unwrap().starts_with("127.0.0.1:"));
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
+
+struct Calls(AtomicUsize);
+
+struct ReportCalls(Mutex<Sender<usize>>);
+
+impl ConnectionHook for ReportCalls {
+    fn on_disconnect(&self, conn: &ConnectionInfo) {
+        let calls = conn.session::<Calls>().unwrap().0.load(Ordering::SeqCst);
+        self.0.lock().unwrap().send(calls).unwrap();
+    }
+}
+
+#[test]
+fn server_keeps_a_session_per_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let count: JsonRpcHandler =
+        RpcHandler::AsyncWithContext(Box::new(|ctx: &RequestContext, _: &Vec<Json>| {
+            let calls = ctx.session::<Calls>().unwrap();
+            let (tx, rx) = oneshot::channel();
+            tx.send(Json::from(calls.0.fetch_add(1, Ordering::SeqCst) + 1))
+                .unwrap();
+            rx
+        }));
+    let (report, reported) = channel();
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .session(|_| Calls(AtomicUsize::new(0)))
+        .connection_hook(Arc::new(ReportCalls(Mutex::new(report))))
+        .build_handlers(btreemap! { "test/count".to_owned() => count })
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(5)));
+
+    let mut core = Core::new().unwrap();
+    let mut clients = Vec::new();
+    for expected in 1..3 {
+        let connect = ClientManager::new().connect(&mut core, &addr);
+        let client = core.run(connect).unwrap();
+        for calls in 1..expected + 1 {
+            let result = core.run(client.rpc_call("test", "count", vec![])).unwrap();
+            assert_eq!(result, Json::from(calls));
+        }
+        clients.push(client);
+    }
+
+    // the server closes the connections when shutting down
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+    let mut calls: Vec<usize> = reported.try_iter().collect();
+    calls.sort();
+    assert_eq!(calls, vec![1, 2]);
+}
@@ -0,0 +1,95 @@
+#[macro_use]
+extern crate maplit;
+
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{ClientManager, JsonRpcFn, PrometheusMetrics, ServerBuilder};
+
+#[test]
+fn metrics_endpoint_serves_the_collected_metrics() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // a free port for the endpoint to bind
+    let metrics_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let echo: JsonRpcFn = Box::new(|args: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(Json::Array(args.clone())).unwrap();
+        rx
+    });
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .metrics(Arc::new(PrometheusMetrics::new("slacker")))
+        .metrics_endpoint(metrics_addr)
+        .build(btreemap! { "test/echo".to_owned() => echo })
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+    core.run(client.rpc_call("test", "echo", vec![Json::from(1)]))
+        .unwrap();
+    core.run(client.rpc_call("test", "echo", vec![Json::from(2)]))
+        .unwrap();
+    assert!(core
+        .run(client.rpc_call("test", "missing", vec![]))
+        .is_err());
+
+    let mut scrape = TcpStream::connect(metrics_addr).unwrap();
+    scrape.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    scrape.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    let lines: Vec<&str> = response.lines().collect();
+    for expected in &[
+        "# TYPE slacker_calls_total counter",
+        "slacker_calls_total{function=\"test/echo\",result=\"0\"} 2",
+        "slacker_calls_total{function=\"test/missing\",result=\"11\"} 1",
+        "# TYPE slacker_call_duration_seconds histogram",
+        "slacker_call_duration_seconds_bucket{function=\"test/echo\",result=\"0\",le=\"+Inf\"} 2",
+        "slacker_call_duration_seconds_count{function=\"test/echo\",result=\"0\"} 2",
+        "slacker_in_flight 0",
+        "slacker_connections 1",
+    ] {
+        assert!(
+            lines.contains(expected),
+            "{} missing from {}",
+            expected,
+            response
+        );
+    }
+    let counter = |name: &str| -> u64 {
+        let prefix = format!("{} ", name);
+        lines
+            .iter()
+            .find(|line| line.starts_with(&prefix))
+            .map(|line| line[prefix.len()..].parse().unwrap())
+            .unwrap()
+    };
+    assert!(counter("slacker_bytes_read_total") > 0);
+    assert!(counter("slacker_bytes_written_total") > 0);
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
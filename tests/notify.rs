@@ -0,0 +1,206 @@
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{
+    Acl, Authenticator, ClientManager, ConnectionInfo, JsonRpcFn, JsonRpcHandler, Metrics,
+    Notifier, RequestContext, RpcHandler, ServerBuilder,
+};
+
+fn publish() -> JsonRpcHandler {
+    RpcHandler::AsyncWithContext(Box::new(|ctx: &RequestContext, args: &Vec<Json>| {
+        ctx.notifier().notify(&args[0]).unwrap();
+        let (tx, rx) = oneshot::channel();
+        tx.send(Json::Null).unwrap();
+        rx
+    }))
+}
+
+#[test]
+fn notifications_reach_every_subscriber_until_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let kept = Arc::new(Mutex::new(None::<Notifier>));
+    let kept_by_handler = kept.clone();
+    let publish: JsonRpcHandler =
+        RpcHandler::AsyncWithContext(Box::new(move |ctx: &RequestContext, args: &Vec<Json>| {
+            ctx.notifier().notify(&args[0]).unwrap();
+            *kept_by_handler.lock().unwrap() = Some(ctx.notifier().clone());
+            let (tx, rx) = oneshot::channel();
+            tx.send(Json::Null).unwrap();
+            rx
+        }));
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build_handlers(
+            vec![("test/publish".to_owned(), publish)]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(5)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+
+    // kept until there is a subscriber
+    core.run(client.rpc_call("test", "publish", vec![Json::from(1)]))
+        .unwrap();
+    let first = client.notifications();
+    let (one, first) = core.run(first.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(one, Some(Json::from(1)));
+
+    let second = client.notifications();
+    core.run(client.rpc_call("test", "publish", vec![Json::from(2)]))
+        .unwrap();
+    let (two, first) = core.run(first.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(two, Some(Json::from(2)));
+    let (two, second) = core.run(second.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(two, Some(Json::from(2)));
+
+    // open subscriptions are no calls in flight holding up the shutdown
+    let shutting_down = Instant::now();
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+    assert!(shutting_down.elapsed() < Duration::from_secs(4));
+    for notifications in [first, second].iter_mut() {
+        let e = core
+            .run(notifications.into_future())
+            .map_err(|(e, _)| e)
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+    let notifier = kept.lock().unwrap().take().unwrap();
+    assert_eq!(
+        notifier.notify(&3).err().unwrap().kind(),
+        io::ErrorKind::BrokenPipe
+    );
+}
+
+struct Token;
+
+impl Authenticator for Token {
+    fn authenticate(&self, _: &ConnectionInfo, token: Option<&[u8]>) -> Option<String> {
+        match token {
+            Some(b"secret") => Some("alice".to_owned()),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn dropped_subscriptions_end_without_a_function_grant() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .auth(
+            Arc::new(Token),
+            Some(Arc::new(Acl::new().allow("alice", "test/*"))),
+        )
+        .build_handlers(
+            vec![("test/publish".to_owned(), publish())]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(5)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new()
+        .with_auth_token(b"secret".to_vec())
+        .connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+
+    let first = client.notifications();
+    core.run(client.rpc_call("test", "publish", vec![Json::from(1)]))
+        .unwrap();
+    let (one, first) = core.run(first.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(one, Some(Json::from(1)));
+
+    // kept for the next subscriber once the only one is gone
+    drop(first);
+    core.run(client.rpc_call("test", "publish", vec![Json::from(2)]))
+        .unwrap();
+    let second = client.notifications();
+    let (two, _) = core.run(second.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(two, Some(Json::from(2)));
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
+
+#[derive(Default)]
+struct Written(AtomicUsize);
+
+impl Metrics for Written {
+    fn bytes_written(&self, bytes: usize) {
+        self.0.fetch_add(bytes, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn one_way_calls_run_without_a_response() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (called, calls) = mpsc::channel();
+    let called = Mutex::new(called);
+    let record: JsonRpcFn = Box::new(move |args: &Vec<Json>| {
+        called.lock().unwrap().send(args[0].clone()).unwrap();
+        let (tx, rx) = oneshot::channel();
+        tx.send(Json::Null).unwrap();
+        rx
+    });
+    let written = Arc::new(Written::default());
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .metrics(written.clone())
+        .build(
+            vec![("test/record".to_owned(), record)]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(5)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+    client
+        .notify("test", "record", vec![Json::from(1)])
+        .unwrap();
+    core.run(client.ping()).unwrap();
+    let call = calls.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(call, Json::from(1));
+
+    // only the pings were answered
+    let one_ping = written.0.load(Ordering::SeqCst);
+    assert!(one_ping > 0);
+    core.run(client.ping()).unwrap();
+    assert_eq!(written.0.load(Ordering::SeqCst), 2 * one_ping);
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
@@ -0,0 +1,119 @@
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::collections::BTreeMap;
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{
+    ClientManager, JobQueue, JsonRpcHandler, QueuePolicy, RpcHandler, ServerBuilder,
+    ThreadPoolServer,
+};
+
+fn thread_id() -> Json {
+    Json::from(format!("{:?}", thread::current().id()))
+}
+
+#[test]
+fn assigned_functions_run_on_their_own_pool() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (release, released) = mpsc::channel::<()>();
+    let released = Mutex::new(released);
+    let block: JsonRpcHandler = RpcHandler::Blocking(Arc::new(move |_| {
+        released.lock().unwrap().recv().unwrap();
+        thread_id()
+    }));
+    let id: JsonRpcHandler = RpcHandler::Blocking(Arc::new(|_| thread_id()));
+    let mut funcs = BTreeMap::new();
+    funcs.insert("slow/block".to_owned(), block);
+    funcs.insert("fast/id".to_owned(), id);
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build_mixed(funcs, 1)
+        .unwrap()
+        .with_pool("slow", JobQueue::new(1, 1, QueuePolicy::Reject))
+        .assign_pool("slow/*", "slow");
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(5)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+
+    // one running and one queued fill the slow pool
+    let running = client.rpc_call("slow", "block", vec![]);
+    let queued = client.rpc_call("slow", "block", vec![]);
+    let e = core
+        .run(client.rpc_call("slow", "block", vec![]))
+        .unwrap_err();
+    assert_eq!(e.to_string(), "Server busy.");
+
+    // while the default pool still serves the others
+    let fast = core.run(client.rpc_call("fast", "id", vec![])).unwrap();
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    let (first, second) = core.run(running.join(queued)).unwrap();
+    assert_eq!(first, second);
+    assert_ne!(first, fast);
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
+
+#[test]
+fn mixed_servers_serve_async_and_blocking_functions() {
+    // a free port for the server to bind
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let on_reactor: JsonRpcHandler = RpcHandler::Async(Box::new(|_: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(thread_id()).unwrap();
+        rx
+    }));
+    let on_pool: JsonRpcHandler = RpcHandler::Blocking(Arc::new(|_| thread_id()));
+    let mut funcs = BTreeMap::new();
+    funcs.insert("test/async".to_owned(), on_reactor);
+    funcs.insert("test/blocking".to_owned(), on_pool);
+    let server = ThreadPoolServer::new_mixed(addr, funcs, 1);
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving = thread::spawn(move || {
+        let reactor = thread_id();
+        server
+            .serve_until(stopped.map_err(|_| ()), Duration::from_secs(5))
+            .map(|()| reactor)
+    });
+
+    let mut core = Core::new().unwrap();
+    let manager = ClientManager::new();
+    let client = loop {
+        let connect = manager.connect_with_handle(&core.handle(), &addr);
+        match core.run(connect) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let calls = client
+        .rpc_call("test", "async", vec![])
+        .join(client.rpc_call("test", "blocking", vec![]));
+    let (on_reactor, on_pool) = core.run(calls).unwrap();
+
+    shutdown.send(()).unwrap();
+    let reactor = serving.join().unwrap().unwrap();
+    assert_eq!(on_reactor, reactor);
+    assert_ne!(on_pool, reactor);
+}
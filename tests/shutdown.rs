@@ -0,0 +1,56 @@
+#[macro_use]
+extern crate maplit;
+
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{ClientManager, JsonRpcFn, ServerBuilder};
+
+#[test]
+fn shutdown_answers_in_flight_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let shutdown = Mutex::new(Some(shutdown));
+
+    // starts the shutdown, then answers a little later
+    let slow: JsonRpcFn = Box::new(move |args: &Vec<Json>| {
+        shutdown.lock().unwrap().take().unwrap().send(()).unwrap();
+        let (tx, rx) = oneshot::channel();
+        let args = args.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            tx.send(Json::Array(args)).unwrap();
+        });
+        rx
+    });
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build(btreemap! { "test/slow".to_owned() => slow })
+        .unwrap();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(5)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+    let result = core
+        .run(client.rpc_call("test", "slow", vec![Json::from(1)]))
+        .unwrap();
+    assert_eq!(result, Json::Array(vec![Json::from(1)]));
+
+    serving.join().unwrap().unwrap();
+    assert!(core.run(client.rpc_call("test", "slow", vec![])).is_err());
+}
@@ -0,0 +1,119 @@
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{empty, Either};
+use futures::stream::iter_ok;
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use serde_json::value::Value as Json;
+use tcore::reactor::{Core, Timeout};
+
+use slacker::{ClientManager, JsonRpcHandler, RpcHandler, RpcStream, ServerBuilder};
+
+fn serve(
+    funcs: Vec<(&str, JsonRpcHandler)>,
+) -> (
+    SocketAddr,
+    oneshot::Sender<()>,
+    thread::JoinHandle<io::Result<()>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .build_handlers(
+            funcs
+                .into_iter()
+                .map(|(fname, f)| (fname.to_owned(), f))
+                .collect(),
+        )
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1)));
+    (addr, shutdown, serving)
+}
+
+#[test]
+fn items_are_pushed_as_they_are_produced() {
+    let (items, produced) = mpsc::channel::<Json>(16);
+    let produced = Mutex::new(Some(produced));
+    let live: JsonRpcHandler = RpcHandler::Streaming(Box::new(move |_: &Vec<Json>| {
+        let stream = produced.lock().unwrap().take().unwrap();
+        Box::new(stream.map_err(|_| io::Error::from(io::ErrorKind::Other))) as RpcStream<Json>
+    }));
+    let many: JsonRpcHandler = RpcHandler::Streaming(Box::new(|_: &Vec<Json>| {
+        Box::new(iter_ok((0..600).map(Json::from))) as RpcStream<Json>
+    }));
+    let (addr, shutdown, serving) = serve(vec![("test/live", live), ("test/many", many)]);
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+
+    // the first item arrives while the stream is still open
+    let items = core.run(items.send(Json::from(1))).unwrap();
+    let (first, rest) = core
+        .run(client.stream_call("test", "live", vec![]).into_future())
+        .map_err(|(e, _)| e)
+        .unwrap();
+    assert_eq!(first, Some(Json::from(1)));
+    let items = core.run(items.send(Json::from(2))).unwrap();
+    drop(items);
+    assert_eq!(core.run(rest.collect()).unwrap(), vec![Json::from(2)]);
+
+    // more items than fit in a chunk
+    let all = core
+        .run(client.stream_call("test", "many", vec![]).collect())
+        .unwrap();
+    assert_eq!(all, (0..600).map(Json::from).collect::<Vec<Json>>());
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
+
+#[test]
+fn open_streams_are_bounded() {
+    let endless: JsonRpcHandler = RpcHandler::Streaming(Box::new(|_: &Vec<Json>| {
+        Box::new(empty::<Json, io::Error>().into_stream()) as RpcStream<Json>
+    }));
+    let (addr, shutdown, serving) = serve(vec![("test/endless", endless)]);
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect(&mut core, &addr);
+    let client = core.run(connect).unwrap();
+    let open: Vec<_> = (0..16)
+        .map(|_| client.stream_call("test", "endless", vec![]))
+        .collect();
+    let e = core
+        .run(client.stream_call("test", "endless", vec![]).into_future())
+        .map_err(|(e, _)| e)
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Server busy.");
+
+    // cancelling one makes room for another
+    drop(open);
+    let ping = client.ping();
+    core.run(ping).unwrap();
+    let timeout = Timeout::new(Duration::from_millis(100), &core.handle()).unwrap();
+    let next = client
+        .stream_call("test", "endless", vec![])
+        .into_future()
+        .select2(timeout);
+    match core.run(next) {
+        Ok(Either::B(_)) => {}
+        _ => panic!("the stream did not stay open"),
+    }
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
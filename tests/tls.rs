@@ -0,0 +1,73 @@
+#![cfg(feature = "tls")]
+#[macro_use]
+extern crate maplit;
+
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{ClientManager, JsonRpcFn, ServerBuilder, TlsClientConfig, TlsServerConfig};
+
+// certificates from tests/tls/gen.sh
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/tls")
+        .join(name)
+}
+
+#[test]
+fn handshakes_count_toward_max_connections_until_they_time_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tls = TlsServerConfig::new(&fixture("server.pem"), &fixture("server.key"))
+        .unwrap()
+        .with_handshake_timeout(Duration::from_millis(300));
+    let echo: JsonRpcFn = Box::new(|args: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(Json::Array(args.clone())).unwrap();
+        rx
+    });
+    let server = ServerBuilder::new()
+        .listener(listener)
+        .max_connections(1)
+        .tls(tls)
+        .build(btreemap! { "test/echo".to_owned() => echo })
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1)));
+
+    let client_tls = TlsClientConfig::new(&fixture("ca.pem")).unwrap();
+    let mut core = Core::new().unwrap();
+    let manager = ClientManager::new();
+
+    // never sends a client hello, so its handshake takes the only slot
+    let stalled = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let connect = manager.connect_tls(&core.handle(), &addr, "localhost", &client_tls);
+    assert!(core.run(connect).is_err());
+
+    // the server closes it once its handshake times out
+    thread::sleep(Duration::from_millis(400));
+    let connect = manager.connect_tls(&core.handle(), &addr, "localhost", &client_tls);
+    let client = core.run(connect).unwrap();
+    let result = core
+        .run(client.rpc_call("test", "echo", vec![Json::from(1)]))
+        .unwrap();
+    assert_eq!(result, Json::Array(vec![Json::from(1)]));
+    drop(stalled);
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+}
@@ -0,0 +1,13 @@
+#!/bin/sh
+# Regenerates the self-signed CA and the localhost certificate used by the
+# TLS tests.
+set -e
+cd "$(dirname "$0")"
+openssl req -x509 -newkey rsa:2048 -nodes -days 3650 -subj "/CN=slacker test ca" \
+    -keyout ca.key -out ca.pem
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+    -keyout server.key -out server.csr
+printf "subjectAltName=DNS:localhost\nbasicConstraints=CA:FALSE\n" > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 3650 \
+    -extfile server.ext -out server.pem
+rm -f ca.key ca.srl server.csr server.ext
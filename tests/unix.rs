@@ -0,0 +1,66 @@
+#![cfg(unix)]
+#[macro_use]
+extern crate maplit;
+
+extern crate futures;
+extern crate serde_json;
+extern crate slacker;
+extern crate tokio_core as tcore;
+
+use std::env;
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json::value::Value as Json;
+use tcore::reactor::Core;
+
+use slacker::{
+    ClientManager, ConnectionHook, ConnectionInfo, JsonRpcFn, ServerBuilder, SlackerAddr,
+};
+
+struct Peers(Mutex<Vec<Option<SlackerAddr>>>);
+
+impl ConnectionHook for Peers {
+    fn on_connect(&self, conn: &ConnectionInfo) {
+        self.0.lock().unwrap().push(conn.peer_addr.clone());
+    }
+}
+
+#[test]
+fn unix_socket_file_lifecycle() {
+    let path = env::temp_dir().join(format!("slacker-{}.sock", ::std::process::id()));
+    // a socket file left behind by a server that is gone
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let echo: JsonRpcFn = Box::new(|args: &Vec<Json>| {
+        let (tx, rx) = oneshot::channel();
+        tx.send(Json::Array(args.clone())).unwrap();
+        rx
+    });
+    let peers = Arc::new(Peers(Mutex::new(Vec::new())));
+    let addr = SlackerAddr::Unix(path.clone());
+    let server = ServerBuilder::new()
+        .bind_addr(addr.clone())
+        .connection_hook(peers.clone())
+        .build(btreemap! { "test/echo".to_owned() => echo })
+        .unwrap();
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let serving =
+        thread::spawn(move || server.serve_until(stopped.map_err(|_| ()), Duration::from_secs(1)));
+
+    let mut core = Core::new().unwrap();
+    let connect = ClientManager::new().connect_addr(&core.handle(), &addr);
+    let client = core.run(connect).unwrap();
+    let result = core.run(client.rpc_call("test", "echo", vec![])).unwrap();
+    assert_eq!(result, Json::Array(vec![]));
+    assert_eq!(*peers.0.lock().unwrap(), vec![None]);
+
+    shutdown.send(()).unwrap();
+    serving.join().unwrap().unwrap();
+    assert!(!path.exists());
+}